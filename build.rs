@@ -13,10 +13,17 @@ fn main() {
     let uefipath = Path::new(&out_dir).join("snakian-uefi.img");
     let biospath = Path::new(&out_dir).join("snakian-bios.img");
 
-    let uefi = UefiBoot::new(Path::new(&kdir));
-    uefi.create_disk_image(&uefipath).unwrap();
-    let bios = BiosBoot::new(Path::new(&kdir));
-    bios.create_disk_image(&biospath).unwrap();
+    // The kernel symbols are written into the kernel image itself, for backtraces.
+    let kernelpath = Path::new(&out_dir).join("snakian-kernel");
+    let mut kernel = fs::read(&kdir).unwrap();
+    let symbols = symbol_table(&kernel);
+    if let Err(err) = embed_symbols(&mut kernel, &symbols) {
+        println!("cargo:warning=backtraces won't have symbol names: {}", err);
+    }
+    fs::write(&kernelpath, &kernel).unwrap();
+
+    UefiBoot::new(&kernelpath).create_disk_image(&uefipath).unwrap();
+    BiosBoot::new(&kernelpath).create_disk_image(&biospath).unwrap();
 
     let target_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
         .join("target")
@@ -31,3 +38,175 @@ fn main() {
     println!("cargo:rustc-env=UEFI_IMAGE={}", uefi_out.display());
     println!("cargo:rustc-env=BIOS_IMAGE={}", bios_out.display());
 }
+
+/// Marks the start of the symbol table, the kernel checks it before using it.
+const SYMBOLS_MAGIC: &[u8; 8] = b"SNKSYMS1";
+/// The section the kernel reserves for the symbol table, see `snakian_kernel/src/backtrace.rs`.
+const SYMBOLS_SECTION: &[u8] = b".snakian_symbols";
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const ET_DYN: u16 = 3;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Builds the symbol table the kernel uses for backtraces from the function symbols of the kernel ELF.
+///
+/// The layout is the magic, the symbol count (u32) and whether addresses still need the load offset added (u32),
+/// then one entry per symbol sorted by address: address (u64), size (u64), name offset and length (u32 each),
+/// relative to the string data that follows the entries.
+fn symbol_table(elf: &[u8]) -> Vec<u8> {
+    let relocatable = read_u16(elf, 16) == ET_DYN;
+    let shoff = read_u64(elf, 40) as usize;
+    let shentsize = read_u16(elf, 58) as usize;
+    let shnum = read_u16(elf, 60) as usize;
+    let section = |index: usize| shoff + index * shentsize;
+
+    let mut symbols = Vec::new();
+    for index in 0..shnum {
+        let header = section(index);
+        if read_u32(elf, header + 4) != SHT_SYMTAB {
+            continue;
+        }
+        let offset = read_u64(elf, header + 24) as usize;
+        let size = read_u64(elf, header + 32) as usize;
+        let entsize = read_u64(elf, header + 56) as usize;
+        let strtab = read_u64(elf, section(read_u32(elf, header + 40) as usize) + 24) as usize;
+        for sym in (offset..offset + size).step_by(entsize) {
+            let value = read_u64(elf, sym + 8);
+            if elf[sym + 4] & 0xf != STT_FUNC || value == 0 {
+                continue;
+            }
+            let name_start = strtab + read_u32(elf, sym) as usize;
+            let name_len = elf[name_start..].iter().position(|b| *b == 0).unwrap();
+            let name = String::from_utf8_lossy(&elf[name_start..name_start + name_len]);
+            symbols.push((value, read_u64(elf, sym + 16), demangle(&name)));
+        }
+    }
+    symbols.sort();
+    symbols.dedup_by_key(|(addr, _, _)| *addr);
+
+    let mut table = Vec::new();
+    let mut strings = Vec::new();
+    table.extend_from_slice(SYMBOLS_MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(relocatable as u32).to_le_bytes());
+    for (addr, size, name) in &symbols {
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        strings.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&strings);
+    table
+}
+
+/// Writes the symbol table over the placeholder in the symbols section of the kernel ELF.
+fn embed_symbols(elf: &mut [u8], symbols: &[u8]) -> Result<(), String> {
+    let shoff = read_u64(elf, 40) as usize;
+    let shentsize = read_u16(elf, 58) as usize;
+    let shnum = read_u16(elf, 60) as usize;
+    let section = |index: usize| shoff + index * shentsize;
+    let names = read_u64(elf, section(read_u16(elf, 62) as usize) + 24) as usize;
+
+    let header = (0..shnum)
+        .map(section)
+        .find(|header| {
+            let name = &elf[names + read_u32(elf, *header) as usize..];
+            name.starts_with(SYMBOLS_SECTION) && name.get(SYMBOLS_SECTION.len()) == Some(&0)
+        })
+        .ok_or("the kernel has no symbols section")?;
+    let offset = read_u64(elf, header + 24) as usize;
+    let size = read_u64(elf, header + 32) as usize;
+    if symbols.len() > size {
+        return Err(format!(
+            "the symbol table takes {} bytes, but the kernel only has room for {}",
+            symbols.len(),
+            size
+        ));
+    }
+    elf[offset..offset + symbols.len()].copy_from_slice(symbols);
+    Ok(())
+}
+
+/// Demangles a legacy Rust symbol (`_ZN` followed by length prefixed path segments and `E`), dropping the hash.
+/// Anything else is returned as is.
+fn demangle(symbol: &str) -> String {
+    let Some(mut rest) = symbol.strip_prefix("_ZN") else {
+        return symbol.to_string();
+    };
+    let mut segments = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Ok(len) = rest[..digits].parse::<usize>() else {
+            return symbol.to_string();
+        };
+        if rest.len() < digits + len {
+            return symbol.to_string();
+        }
+        segments.push(&rest[digits..digits + len]);
+        rest = &rest[digits + len..];
+    }
+    if let Some(last) = segments.last() {
+        if last.len() == 17 && last.starts_with('h') && last[1..].bytes().all(|b| b.is_ascii_hexdigit()) {
+            segments.pop();
+        }
+    }
+    let path = segments
+        .iter()
+        .map(|segment| unescape(segment.strip_prefix('_').filter(|s| s.starts_with('$')).unwrap_or(segment)))
+        .collect::<Vec<_>>();
+    path.join("::")
+}
+
+/// Replaces the `$..$` escapes of legacy mangling with the characters they stand for.
+fn unescape(segment: &str) -> String {
+    let mut out = String::new();
+    let mut rest = segment;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('$') {
+            let Some(end) = after.find('$') else {
+                out.push_str(rest);
+                break;
+            };
+            let escape = &after[..end];
+            let replacement = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            match replacement {
+                Some(c) => out.push(c),
+                None => out.push_str(&rest[..end + 2]),
+            }
+            rest = &after[end + 1..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
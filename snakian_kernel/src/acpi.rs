@@ -0,0 +1,505 @@
+//! ACPI table discovery and parsing.
+//!
+//! The bootloader reports where the RSDP is. From there the RSDT (or XSDT on ACPI 2.0+) lists the physical addresses
+//! of every other table. Tables are only used once their checksum is valid, and are read through the physical memory
+//! mapping with bounds checked accessors, so nothing outside of a table is ever read by accident.
+//!
+//! The tables the kernel cares about are parsed into plain structs: [`madt`], [`fadt`], [`hpet`] and [`mcfg`].
+//! [`dump_tables`] lists every table that was found.
+
+use core::{fmt, mem};
+
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+use crate::{memory::phys_to_virt, prelude::*};
+
+/// The size of the header every system description table starts with.
+const SDT_HEADER_SIZE: u64 = 36;
+/// The size of the ACPI 1.0 part of the RSDP, covered by the first checksum.
+const RSDP_V1_SIZE: u64 = 20;
+/// The size of the ACPI 2.0 RSDP, covered by the extended checksum.
+const RSDP_V2_SIZE: u64 = 36;
+
+/// The root table all other tables are found through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RootTable {
+    /// ACPI 1.0, 32 bit table pointers.
+    Rsdt(Sdt),
+    /// ACPI 2.0+, 64 bit table pointers.
+    Xsdt(Sdt),
+}
+
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::uninit();
+
+/// Reads a value of type `T` at the given physical address.
+/// # Safety
+/// The address must be readable through the physical memory mapping.
+unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { phys_to_virt(addr).as_ptr::<T>().read_unaligned() }
+}
+
+/// Returns true if the bytes at `addr..addr + len` add up to zero.
+/// # Safety
+/// The range must be readable through the physical memory mapping.
+unsafe fn checksum_ok(addr: PhysAddr, len: u64) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len as usize) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// A validated system description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sdt {
+    addr: PhysAddr,
+    length: u32,
+}
+
+impl Sdt {
+    /// Validates the table at `addr`. Returns `None` if the checksum doesn't match.
+    /// # Safety
+    /// `addr` must point to readable memory that is at least as long as the length in the table header says.
+    unsafe fn new(addr: PhysAddr) -> Option<Sdt> {
+        let length: u32 = unsafe { read_phys(addr + 4u64) };
+        if (length as u64) < SDT_HEADER_SIZE || !unsafe { checksum_ok(addr, length as u64) } {
+            return None;
+        }
+        Some(Sdt { addr, length })
+    }
+
+    /// The physical address of the table.
+    pub fn address(&self) -> PhysAddr {
+        self.addr
+    }
+
+    /// The length of the table, including the header.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// Reads a value at `offset` bytes into the table. Returns `None` if it would reach past the end of the table.
+    pub fn read<T: Copy>(&self, offset: u64) -> Option<T> {
+        if offset + mem::size_of::<T>() as u64 > self.length as u64 {
+            return None;
+        }
+        // SAFETY: the table was validated, and the read is inside of it.
+        Some(unsafe { read_phys(self.addr + offset) })
+    }
+
+    /// The whole table, including the header.
+    pub fn bytes(&self) -> &'static [u8] {
+        // SAFETY: the table was validated, so all of it is readable.
+        unsafe { core::slice::from_raw_parts(phys_to_virt(self.addr).as_ptr::<u8>(), self.length as usize) }
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.read(0).unwrap()
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.read(8).unwrap()
+    }
+
+    pub fn oem_id(&self) -> [u8; 6] {
+        self.read(10).unwrap()
+    }
+
+    pub fn oem_table_id(&self) -> [u8; 8] {
+        self.read(16).unwrap()
+    }
+}
+
+impl fmt::Display for Sdt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#010x}, {:5} bytes, rev {}, OEM {} {}",
+            Ascii(&self.signature()),
+            self.addr.as_u64(),
+            self.length,
+            self.revision(),
+            Ascii(&self.oem_id()),
+            Ascii(&self.oem_table_id())
+        )
+    }
+}
+
+/// Formats ACPI identifier bytes, replacing anything that isn't printable.
+struct Ascii<'a>(&'a [u8]);
+
+impl fmt::Display for Ascii<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            let c = if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '?' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the root table through the RSDP at `rsdp_addr`. Returns false if there is no valid RSDP.
+pub fn init(rsdp_addr: Option<u64>) -> bool {
+    let Some(rsdp_addr) = rsdp_addr else {
+        warn!("No RSDP reported by the bootloader, ACPI is unavailable");
+        return false;
+    };
+    let rsdp = PhysAddr::new(rsdp_addr);
+    // SAFETY: the bootloader reported an RSDP here, and all of physical memory is mapped.
+    let signature: [u8; 8] = unsafe { read_phys(rsdp) };
+    if &signature != b"RSD PTR " {
+        warn!("Invalid RSDP signature at {:#x}", rsdp_addr);
+        return false;
+    }
+    if !unsafe { checksum_ok(rsdp, RSDP_V1_SIZE) } {
+        warn!("Invalid RSDP checksum at {:#x}", rsdp_addr);
+        return false;
+    }
+    let revision: u8 = unsafe { read_phys(rsdp + 15u64) };
+    // The XSDT is only used if the extended RSDP is valid as well, otherwise fall back to the RSDT.
+    let root = if revision >= 2 && unsafe { checksum_ok(rsdp, RSDP_V2_SIZE) } {
+        let xsdt = PhysAddr::new(unsafe { read_phys::<u64>(rsdp + 24u64) });
+        unsafe { Sdt::new(xsdt) }.map(RootTable::Xsdt)
+    } else {
+        None
+    };
+    let root = root.or_else(|| {
+        let rsdt = PhysAddr::new(unsafe { read_phys::<u32>(rsdp + 16u64) } as u64);
+        unsafe { Sdt::new(rsdt) }.map(RootTable::Rsdt)
+    });
+    let Some(root) = root else {
+        warn!("ACPI root table has an invalid checksum");
+        return false;
+    };
+    info!("ACPI revision {} root table: {:?}", revision, root);
+    ROOT_TABLE.init_once(|| root);
+    for_each_entry(|addr, table| match table {
+        Some(table) => debug!("ACPI table {}", table),
+        None => warn!("ACPI table at {:#x} has an invalid checksum, ignoring it", addr.as_u64()),
+    });
+    true
+}
+
+/// Calls `f` for every table listed in the root table, with `None` for tables whose checksum is invalid.
+fn for_each_entry(mut f: impl FnMut(PhysAddr, Option<Sdt>)) {
+    let Some(root) = ROOT_TABLE.get() else {
+        return;
+    };
+    let (root, entry_size) = match *root {
+        RootTable::Rsdt(sdt) => (sdt, 4),
+        RootTable::Xsdt(sdt) => (sdt, 8),
+    };
+    let entries = (root.length as u64 - SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let offset = SDT_HEADER_SIZE + i * entry_size;
+        let addr = match entry_size {
+            4 => root.read::<u32>(offset).map(u64::from),
+            _ => root.read::<u64>(offset),
+        };
+        let Some(addr) = addr.map(PhysAddr::new) else {
+            continue;
+        };
+        // SAFETY: the root table lists this as a table.
+        f(addr, unsafe { Sdt::new(addr) });
+    }
+}
+
+/// Calls `f` for every valid table.
+pub fn for_each_table(mut f: impl FnMut(Sdt)) {
+    for_each_entry(|_, table| {
+        if let Some(table) = table {
+            f(table);
+        }
+    });
+}
+
+/// Returns the first valid table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt> {
+    let mut found = None;
+    for_each_table(|table| {
+        if found.is_none() && table.signature() == *signature {
+            found = Some(table);
+        }
+    });
+    found
+}
+
+/// Prints every table the root table lists to the serial port.
+pub fn dump_tables() {
+    let Some(root) = ROOT_TABLE.get() else {
+        serial_println!("ACPI is unavailable");
+        return;
+    };
+    let (RootTable::Rsdt(sdt) | RootTable::Xsdt(sdt)) = *root;
+    serial_println!("ACPI tables:");
+    serial_println!("  {}", sdt);
+    for_each_entry(|addr, table| match table {
+        Some(table) => serial_println!("  {}", table),
+        None => serial_println!("  table at {:#010x} has an invalid checksum", addr.as_u64()),
+    });
+}
+
+/// A processor described by the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// The processor's ID in the ACPI namespace.
+    pub acpi_id: u32,
+    pub apic_id: u32,
+}
+
+/// An I/O APIC described by the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// An interrupt source override: an ISA IRQ that isn't identity mapped to a global system interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// The MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// True if the interrupt is active low. ISA interrupts are active high unless overridden.
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    /// True if the interrupt is level triggered. ISA interrupts are edge triggered unless overridden.
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// True if the system also has 8259 PICs.
+    pub has_pics: bool,
+    /// All enabled processors.
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+/// Finds and parses the MADT.
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(table.read::<u32>(SDT_HEADER_SIZE)? as u64),
+        has_pics: table.read::<u32>(SDT_HEADER_SIZE + 4)? & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while let (Some(kind), Some(len)) = (table.read::<u8>(offset), table.read::<u8>(offset + 1)) {
+        if len < 2 {
+            warn!("Malformed MADT entry at offset {}", offset);
+            break;
+        }
+        let field = |at: u64| offset + at;
+        match kind {
+            // Processor local APIC, only counted if it is enabled.
+            0 if table.read::<u32>(field(4))? & 1 != 0 => madt.processors.push(Processor {
+                acpi_id: table.read::<u8>(field(2))? as u32,
+                apic_id: table.read::<u8>(field(3))? as u32,
+            }),
+            1 => madt.io_apics.push(IoApicInfo {
+                id: table.read(field(2))?,
+                address: PhysAddr::new(table.read::<u32>(field(4))? as u64),
+                gsi_base: table.read(field(8))?,
+            }),
+            2 => madt.overrides.push(InterruptOverride {
+                irq: table.read(field(3))?,
+                gsi: table.read(field(4))?,
+                flags: table.read(field(8))?,
+            }),
+            // Local APIC address override, for a 64 bit address.
+            5 => madt.local_apic_address = PhysAddr::new(table.read(field(4))?),
+            // Processor local x2APIC.
+            9 if table.read::<u32>(field(8))? & 1 != 0 => madt.processors.push(Processor {
+                acpi_id: table.read(field(12))?,
+                apic_id: table.read(field(4))?,
+            }),
+            _ => {}
+        }
+        offset += len as u64;
+    }
+    Some(madt)
+}
+
+/// The address space a [`GenericAddress`] is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// An ACPI Generic Address Structure: a register somewhere in memory, I/O or PCI configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    fn read_from(table: &Sdt, offset: u64) -> Option<GenericAddress> {
+        let space = match table.read::<u8>(offset)? {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        Some(GenericAddress {
+            space,
+            bit_width: table.read(offset + 1)?,
+            bit_offset: table.read(offset + 2)?,
+            access_size: table.read(offset + 3)?,
+            address: table.read(offset + 4)?,
+        })
+    }
+}
+
+/// The Fixed ACPI Description Table. Port addresses of zero mean the block doesn't exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// The physical address of the DSDT.
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    /// The port ACPI mode is enabled through, zero if the system is always in ACPI mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    /// The CMOS RTC register holding the century, zero if there is none.
+    pub century_register: u8,
+    /// IA-PC boot architecture flags (bit 1: the system has an 8042 keyboard controller).
+    pub boot_architecture: u16,
+    pub flags: u32,
+    /// The register that resets the system when `reset_value` is written to it.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Bit 10 of the FADT flags: the reset register is supported.
+    const RESET_REG_SUP: u32 = 1 << 10;
+
+    /// Returns true if the system has an 8042 keyboard controller, or doesn't say otherwise.
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture & (1 << 1) != 0
+    }
+}
+
+/// Finds and parses the FADT.
+pub fn fadt() -> Option<Fadt> {
+    let table = find_table(b"FACP")?;
+    // ACPI 2.0+ has a 64 bit DSDT pointer, which takes precedence if it is set.
+    let dsdt = match table.read::<u64>(140) {
+        Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+        _ => table.read::<u32>(40)? as u64,
+    };
+    // Revision 1 FADTs come from ACPI 1.0, which had no reset register.
+    let revision = table.revision();
+    let flags = match revision {
+        2.. => table.read::<u32>(112).unwrap_or(0),
+        _ => 0,
+    };
+    let reset_register = (flags & Fadt::RESET_REG_SUP != 0)
+        .then(|| GenericAddress::read_from(&table, 116))
+        .flatten();
+    Some(Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        sci_interrupt: table.read(46)?,
+        smi_command_port: table.read(48)?,
+        acpi_enable: table.read(52)?,
+        acpi_disable: table.read(53)?,
+        pm1a_event_block: table.read(56)?,
+        pm1b_event_block: table.read(60)?,
+        pm1a_control_block: table.read(64)?,
+        pm1b_control_block: table.read(68)?,
+        pm_timer_block: table.read(76)?,
+        century_register: table.read(108)?,
+        // ACPI 1.0 FADTs have this byte reserved, those systems always have an 8042.
+        boot_architecture: match revision {
+            2.. => table.read(109).unwrap_or(1 << 1),
+            _ => 1 << 1,
+        },
+        flags,
+        reset_register,
+        reset_value: table.read(128).unwrap_or(0),
+    })
+}
+
+/// Returns the DSDT, the table holding the AML definition blocks of the system.
+pub fn dsdt() -> Option<Sdt> {
+    let fadt = fadt()?;
+    // SAFETY: the FADT points to the DSDT, which is in ACPI memory.
+    let dsdt = unsafe { Sdt::new(fadt.dsdt) }?;
+    (&dsdt.signature() == b"DSDT").then_some(dsdt)
+}
+
+/// The High Precision Event Timer description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// The hardware revision, comparator count and vendor of the first timer block.
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// The minimum tick count that can be programmed in periodic mode without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+/// Finds and parses the HPET table.
+pub fn hpet() -> Option<Hpet> {
+    let table = find_table(b"HPET")?;
+    Some(Hpet {
+        event_timer_block_id: table.read(36)?,
+        base_address: GenericAddress::read_from(&table, 40)?,
+        hpet_number: table.read(52)?,
+        minimum_tick: table.read(53)?,
+    })
+}
+
+/// A PCI Express enhanced configuration space window from the MCFG table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: PhysAddr,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Finds and parses the MCFG table.
+pub fn mcfg() -> Option<Vec<McfgEntry>> {
+    let table = find_table(b"MCFG")?;
+    let mut entries = Vec::new();
+    // The entries start after 8 reserved bytes.
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while let Some(base) = table.read::<u64>(offset) {
+        entries.push(McfgEntry {
+            base_address: PhysAddr::new(base),
+            segment_group: table.read(offset + 8)?,
+            start_bus: table.read(offset + 10)?,
+            end_bus: table.read(offset + 11)?,
+        });
+        offset += 16;
+    }
+    Some(entries)
+}
@@ -0,0 +1,398 @@
+//! Local APIC and I/O APIC support.
+//!
+//! When the CPU has an APIC and the ACPI MADT describes at least one I/O APIC, [`init`] masks the 8259 PICs and
+//! routes the ISA interrupts through the I/O APIC instead, to the same vectors the PICs used (`PIC_1_OFFSET + irq`).
+//! Interrupt source overrides from the MADT decide which global system interrupt each ISA IRQ arrives on, and with
+//! which polarity and trigger mode. The local APIC is used in x2APIC mode if the CPU supports it.
+//!
+//! I/O APIC redirection entries only have an 8 bit destination in physical mode, and wider destinations would need
+//! interrupt remapping, which isn't supported. A CPU whose x2APIC ID doesn't fit is therefore run in xAPIC mode.
+//!
+//! ISA IRQs can also be delivered as NMIs with [`route_irq_as_nmi`], which the [watchdog](crate::watchdog) uses to
+//! get interrupts that arrive even while the kernel runs with interrupts disabled.
+//!
+//! Handlers don't need to know which controller is active, they signal the end of an interrupt through
+//! [`interrupts::notify_end_of_interrupt`](crate::interrupts::notify_end_of_interrupt).
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+};
+
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    instructions::port::Port,
+    registers::model_specific::Msr,
+    structures::idt::InterruptStackFrame,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::{self, InterruptOverride, IoApicInfo},
+    interrupts::PIC_1_OFFSET,
+    irq,
+    memory::region,
+    prelude::*,
+    sync::IrqMutex,
+};
+
+/// The vector the local APIC delivers spurious interrupts to.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// The first MSR of the x2APIC register block.
+const X2APIC_MSR_BASE: u32 = 0x800;
+/// The highest APIC ID an I/O APIC redirection entry can target without interrupt remapping.
+const MAX_IOAPIC_DESTINATION: u32 = 0xff;
+
+// Local APIC register offsets, as used in xAPIC mode.
+const REG_ID: u32 = 0x20;
+const REG_TPR: u32 = 0x80;
+const REG_EOI: u32 = 0xb0;
+const REG_SVR: u32 = 0xf0;
+const REG_LVT_ERROR: u32 = 0x370;
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+// I/O APIC registers.
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_NMI: u64 = 0b100 << 8;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The amount of legacy ISA interrupts.
+const ISA_IRQS: u8 = 16;
+/// The IRQ the slave 8259 is chained to. It never fires on its own.
+const CASCADE_IRQ: u8 = 2;
+
+/// How the local APIC registers are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LocalApic {
+    /// Memory mapped registers at the contained address.
+    XApic(VirtAddr),
+    /// Registers are model specific registers.
+    X2Apic,
+}
+
+impl LocalApic {
+    fn read(&self, reg: u32) -> u32 {
+        match self {
+            // SAFETY: the register block is mapped, and reading these registers has no side effects.
+            LocalApic::XApic(base) => unsafe { (*base + reg as u64).as_ptr::<u32>().read_volatile() },
+            LocalApic::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32 },
+        }
+    }
+
+    /// # Safety
+    /// Writing local APIC registers can change how interrupts are delivered.
+    unsafe fn write(&self, reg: u32, value: u32) {
+        match self {
+            LocalApic::XApic(base) => unsafe { (*base + reg as u64).as_mut_ptr::<u32>().write_volatile(value) },
+            LocalApic::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(value as u64) },
+        }
+    }
+
+    /// The APIC ID of the current CPU.
+    fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic(_) => self.read(REG_ID) >> 24,
+            LocalApic::X2Apic => self.read(REG_ID),
+        }
+    }
+}
+
+/// A memory mapped I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    /// The amount of redirection entries.
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        // SAFETY: the register window is mapped. The selector and data register are only used with the IO_APICS lock held.
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(reg);
+            (self.base + 0x10u64).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(reg);
+            (self.base + 0x10u64).as_mut_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn write_redirection(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // Write the high half (destination) first, so the entry is never live with a half written destination.
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// Where an ISA IRQ ends up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IsaRoute {
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+static IO_APICS: IrqMutex<Vec<IoApic>> = IrqMutex::new(Vec::new());
+static ISA_ROUTES: OnceCell<[Option<IsaRoute>; ISA_IRQS as usize]> = OnceCell::uninit();
+/// Bit n is set if ISA IRQ n is delivered as an NMI.
+static NMI_IRQS: AtomicU16 = AtomicU16::new(0);
+
+/// Returns true if interrupts are delivered through the APIC instead of the 8259 PICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Signals the end of an interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    if let Some(apic) = LOCAL_APIC.get() {
+        // SAFETY: writing zero to the EOI register only acknowledges the current interrupt.
+        unsafe { apic.write(REG_EOI, 0) };
+    }
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the APIC, if the hardware supports it.
+/// Must be called with interrupts disabled, after the IDT is loaded. Returns true if the APIC is used.
+pub fn init() -> bool {
+    // SAFETY: cpuid is always available in long mode.
+    let features = unsafe { __cpuid(1) };
+    if features.edx & (1 << 9) == 0 {
+        info!("CPU has no APIC, using the 8259 PICs");
+        return false;
+    }
+    let Some(madt) = acpi::madt() else {
+        info!("No MADT found, using the 8259 PICs");
+        return false;
+    };
+    if madt.io_apics.is_empty() {
+        info!("MADT describes no I/O APIC, using the 8259 PICs");
+        return false;
+    }
+    let mut x2apic = features.ecx & (1 << 21) != 0;
+    if x2apic {
+        if let Some(id) = x2apic_id().filter(|id| *id > MAX_IOAPIC_DESTINATION) {
+            warn!(
+                "x2APIC ID {:#x} can't be an I/O APIC destination without interrupt remapping, using xAPIC mode",
+                id
+            );
+            x2apic = false;
+        }
+    }
+
+    let local_apic = match init_local_apic(madt.local_apic_address, x2apic) {
+        Ok(apic) => apic,
+        Err(err) => {
+            warn!("Failed to map the local APIC: {:?}, using the 8259 PICs", err);
+            return false;
+        }
+    };
+    LOCAL_APIC.init_once(|| local_apic);
+    ISA_ROUTES.init_once(|| isa_routes(&madt.overrides));
+    for info in madt.io_apics.iter() {
+        match map_io_apic(info) {
+            Ok(io_apic) => IO_APICS.lock().push(io_apic),
+            Err(err) => warn!("Failed to map I/O APIC {}: {:?}", info.id, err),
+        }
+    }
+
+    disable_pics();
+    route_isa_irqs(local_apic.id());
+    ENABLED.store(true, Ordering::Release);
+    info!(
+        "Using the APIC ({} mode, {} CPUs, {} I/O APICs, {} overrides)",
+        if x2apic { "x2APIC" } else { "xAPIC" },
+        madt.processors.len(),
+        madt.io_apics.len(),
+        madt.overrides.len()
+    );
+    true
+}
+
+/// The x2APIC ID of the current CPU, from the extended topology leaf.
+fn x2apic_id() -> Option<u32> {
+    // SAFETY: cpuid is always available in long mode, and leaf 0xb is only read if it exists.
+    unsafe { (__cpuid(0).eax >= 0xb).then(|| __cpuid_count(0xb, 0).edx) }
+}
+
+/// Enables the local APIC of the current CPU.
+fn init_local_apic(address: PhysAddr, x2apic: bool) -> Result<LocalApic, region::RegionError> {
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    // SAFETY: the CPU has an APIC, so the base MSR exists. Enabling x2APIC mode is allowed when cpuid reports it.
+    let apic = unsafe {
+        let mut base = base_msr.read();
+        if !x2apic && base & APIC_BASE_X2APIC != 0 {
+            // The firmware left x2APIC mode on. Leaving it is only allowed by disabling the APIC first.
+            base &= !(APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            base_msr.write(base);
+        }
+        // Going straight from disabled to x2APIC mode isn't allowed, so always enable xAPIC mode first.
+        base_msr.write(base | APIC_BASE_ENABLE);
+        if x2apic {
+            base_msr.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            LocalApic::X2Apic
+        } else {
+            LocalApic::XApic(region::map_mmio("local apic", address, 4096)?)
+        }
+    };
+    // SAFETY: interrupts are disabled, and the spurious vector has a handler.
+    unsafe {
+        apic.write(REG_TPR, 0);
+        apic.write(REG_LVT_ERROR, LVT_MASKED);
+        apic.write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+    Ok(apic)
+}
+
+fn map_io_apic(info: &IoApicInfo) -> Result<IoApic, region::RegionError> {
+    // SAFETY: the MADT says there is an I/O APIC at this address.
+    let base = unsafe { region::map_mmio("io apic", info.address, 4096)? };
+    let mut io_apic = IoApic {
+        base,
+        gsi_base: info.gsi_base,
+        entries: 0,
+    };
+    io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+    // Start out with everything masked.
+    for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+        io_apic.write_redirection(gsi, REDIRECTION_MASKED);
+    }
+    Ok(io_apic)
+}
+
+/// Builds the ISA IRQ to GSI table. Without an override, IRQ n is GSI n, active high and edge triggered. IRQs
+/// without a line of their own are `None`: the cascade IRQ 2, and any IRQ whose GSI another IRQ is overridden to.
+/// Most firmware sends the PIT's IRQ 0 to GSI 2 this way.
+fn isa_routes(overrides: &[InterruptOverride]) -> [Option<IsaRoute>; ISA_IRQS as usize] {
+    let mut routes = [None; ISA_IRQS as usize];
+    for (irq, route) in routes.iter_mut().enumerate() {
+        if irq == CASCADE_IRQ as usize {
+            continue;
+        }
+        if let Some(o) = overrides.iter().find(|o| o.irq as usize == irq) {
+            *route = Some(IsaRoute {
+                gsi: o.gsi,
+                active_low: o.active_low(),
+                level_triggered: o.level_triggered(),
+            });
+        } else if !overrides.iter().any(|o| o.gsi == irq as u32) {
+            *route = Some(IsaRoute {
+                gsi: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            });
+        }
+    }
+    routes
+}
+
+/// Masks every line of both 8259 PICs. They stay initialized at their offsets, so spurious interrupts they might
+/// still raise land on the IRQ vectors instead of exception vectors.
+fn disable_pics() {
+    // SAFETY: writing the interrupt mask registers only masks interrupts.
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// Programs the redirection entries of every ISA IRQ, unmasking the ones that have handlers registered.
+fn route_isa_irqs(destination: u32) {
+    let Some(routes) = ISA_ROUTES.get() else {
+        return;
+    };
+    for irq in (0..ISA_IRQS).filter(|irq| routes[*irq as usize].is_some()) {
+        let masked = !irq::has_handlers(PIC_1_OFFSET + irq);
+        set_isa_irq(irq, destination, masked);
+    }
+}
+
+fn set_isa_irq(irq: u8, destination: u32, masked: bool) {
+    let Some(routes) = ISA_ROUTES.get() else {
+        return;
+    };
+    let Some(route) = routes[irq as usize] else {
+        // Programming the GSI would clobber the entry of the IRQ that is overridden to it.
+        warn!("IRQ {} has no I/O APIC line of its own", irq);
+        return;
+    };
+    if destination > MAX_IOAPIC_DESTINATION {
+        // The destination field would silently drop the high bits and send the IRQ to some other CPU.
+        warn!("Can't route IRQ {} to APIC ID {:#x}, it doesn't fit the I/O APIC", irq, destination);
+        return;
+    }
+    let io_apics = IO_APICS.lock();
+    let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(route.gsi)) else {
+        warn!("No I/O APIC handles GSI {} (IRQ {})", route.gsi, irq);
+        return;
+    };
+    let mut entry = (PIC_1_OFFSET + irq) as u64 | (destination as u64) << 56;
+    let nmi = NMI_IRQS.load(Ordering::Relaxed) & (1 << irq) != 0;
+    if nmi {
+        // The vector is ignored for NMIs.
+        entry |= REDIRECTION_NMI;
+    }
+    if route.active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if route.level_triggered {
+        entry |= REDIRECTION_LEVEL;
+    }
+    // Handlers come and go on the vector, but NMI routes stay live.
+    if masked && !nmi {
+        entry |= REDIRECTION_MASKED;
+    }
+    io_apic.write_redirection(route.gsi, entry);
+}
+
+/// Masks or unmasks an ISA IRQ at the I/O APIC.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    assert!(irq < ISA_IRQS, "IRQ {} is not an ISA IRQ", irq);
+    let Some(apic) = LOCAL_APIC.get() else {
+        return;
+    };
+    set_isa_irq(irq, apic.id(), masked);
+}
+
+/// Delivers an ISA IRQ to the current CPU as an unmasked NMI, instead of its vector. The IRQ has to be edge
+/// triggered, level triggered NMIs aren't supported by the I/O APIC. Returns false if the APIC isn't used.
+pub fn route_irq_as_nmi(irq: u8) -> bool {
+    assert!(irq < ISA_IRQS, "IRQ {} is not an ISA IRQ", irq);
+    let (Some(apic), Some(routes)) = (LOCAL_APIC.get(), ISA_ROUTES.get()) else {
+        return false;
+    };
+    let Some(route) = routes[irq as usize] else {
+        warn!("IRQ {} has no I/O APIC line of its own", irq);
+        return false;
+    };
+    if route.level_triggered {
+        warn!("IRQ {} is level triggered, it can't be delivered as an NMI", irq);
+        return false;
+    }
+    NMI_IRQS.fetch_or(1 << irq, Ordering::Relaxed);
+    set_isa_irq(irq, apic.id(), false);
+    true
+}
+
+/// The handler for the APIC spurious vector. Spurious interrupts must not be acknowledged.
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    irq::record_spurious(SPURIOUS_VECTOR);
+}
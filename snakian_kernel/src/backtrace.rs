@@ -0,0 +1,252 @@
+//! Stack backtraces with symbol names.
+//!
+//! The kernel is built with frame pointers (see `.cargo/config.toml`), so every frame starts with the frame pointer
+//! of its caller, followed by the return address. [`Backtrace`] follows that chain as long as it stays inside a
+//! registered kernel stack. The precompiled `core` and `alloc` are built without frame pointers, so their frames can be
+//! missing.
+//!
+//! The symbols are embedded in the kernel image. The table can only be built from the linked kernel, so the kernel
+//! reserves room for it in the `.snakian_symbols` section, and the top level `build.rs` extracts the function symbols
+//! of the kernel ELF and writes them into that section before building the boot images. Kernels that didn't go through
+//! that step, like test binaries, show frames as plain addresses.
+
+use core::{arch::asm, fmt, ptr};
+
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+use x86_64::VirtAddr;
+
+use crate::{memory, prelude::*};
+
+/// The maximum amount of frames in a backtrace.
+pub const MAX_FRAMES: usize = 16;
+
+const SYMBOLS_MAGIC: &[u8; 8] = b"SNKSYMS1";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+/// How much room the kernel image has for the symbol table.
+const SYMBOLS_CAPACITY: usize = 512 * 1024;
+
+/// Filled in by the top level `build.rs` after linking. It's mutable and exported so the compiler can't assume it
+/// still holds the placeholder, and the placeholder isn't all zeros so the section takes up space in the ELF file.
+#[no_mangle]
+#[used]
+#[link_section = ".snakian_symbols"]
+static mut EMBEDDED_SYMBOLS: [u8; SYMBOLS_CAPACITY] = {
+    let mut data = [0; SYMBOLS_CAPACITY];
+    data[0] = b'-';
+    data
+};
+
+static SYMBOLS: OnceCell<SymbolTable> = OnceCell::uninit();
+
+/// The function symbols of the kernel, sorted by address.
+pub struct SymbolTable {
+    data: &'static [u8],
+    count: usize,
+    /// Added to the link time addresses to get the addresses the kernel runs at.
+    load_offset: u64,
+}
+
+impl SymbolTable {
+    /// Checks the header of a symbol table, and that the entries and names fit into it.
+    fn parse(data: &'static [u8], kernel_image_offset: u64) -> Option<SymbolTable> {
+        if data.len() < HEADER_SIZE || &data[..8] != SYMBOLS_MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 8) as usize;
+        let relocatable = read_u32(data, 12) != 0;
+        let table = SymbolTable {
+            data,
+            count,
+            load_offset: if relocatable { kernel_image_offset } else { 0 },
+        };
+        let strings = table.strings_start();
+        if strings > data.len() {
+            return None;
+        }
+        let names_fit = (0..count).all(|i| {
+            let (_, _, offset, len) = table.entry(i);
+            strings + offset + len <= data.len()
+        });
+        names_fit.then_some(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn strings_start(&self) -> usize {
+        HEADER_SIZE + self.count * ENTRY_SIZE
+    }
+
+    /// Returns the address, size, name offset and name length of an entry.
+    fn entry(&self, index: usize) -> (u64, u64, usize, usize) {
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        (
+            read_u64(self.data, entry).wrapping_add(self.load_offset),
+            read_u64(self.data, entry + 8),
+            read_u32(self.data, entry + 16) as usize,
+            read_u32(self.data, entry + 20) as usize,
+        )
+    }
+
+    /// Returns the name of the function containing `addr`, and the offset of `addr` into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&'static str, u64)> {
+        // The last symbol that starts at or before the address.
+        let index = self.partition_point(addr).checked_sub(1)?;
+        let (start, size, offset, len) = self.entry(index);
+        if size != 0 && addr >= start + size {
+            return None;
+        }
+        let strings = self.strings_start() + offset;
+        let name = core::str::from_utf8(&self.data[strings..strings + len]).unwrap_or("<invalid symbol>");
+        Some((name, addr - start))
+    }
+
+    /// The amount of symbols that start at or before `addr`.
+    fn partition_point(&self, addr: u64) -> usize {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.entry(mid).0 <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Picks up the symbol table embedded in the kernel image, if the build put one there.
+pub fn init(boot_info: &BootInfo) {
+    // SAFETY: nothing in the kernel writes to the embedded symbols.
+    let data: &'static [u8] = unsafe { &*ptr::addr_of!(EMBEDDED_SYMBOLS) };
+    match SymbolTable::parse(data, boot_info.kernel_image_offset) {
+        Some(table) => {
+            info!("Loaded {} kernel symbols", table.len());
+            SYMBOLS.init_once(|| table);
+        }
+        None => warn!("No symbol table embedded in the kernel, backtraces won't have symbol names"),
+    }
+}
+
+/// Returns the name of the function containing `addr`, and the offset of `addr` into it.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    SYMBOLS.get()?.lookup(addr)
+}
+
+/// The return addresses of a chain of stack frames, innermost first.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    /// The first frame is an instruction pointer rather than a return address.
+    starts_at_rip: bool,
+}
+
+impl Backtrace {
+    /// Captures the frames of the code calling this function.
+    #[inline(never)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        // SAFETY: reading the frame pointer has no side effects.
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        let mut backtrace = Backtrace::empty(false);
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// Captures the frames of interrupted code, starting at its instruction pointer and frame pointer.
+    pub fn from_registers(rip: u64, rbp: u64) -> Backtrace {
+        let mut backtrace = Backtrace::empty(true);
+        backtrace.push(rip);
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    fn empty(starts_at_rip: bool) -> Backtrace {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            starts_at_rip,
+        }
+    }
+
+    fn push(&mut self, addr: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = addr;
+        self.len += 1;
+        true
+    }
+
+    fn walk(&mut self, mut rbp: u64) {
+        // SAFETY: next_frame only reads frames inside of registered kernel stacks.
+        while let Some((ret, next)) = unsafe { next_frame(rbp) } {
+            if ret == 0 || !self.push(ret) {
+                break;
+            }
+            // Callers are further up the stack, anything else means the chain is broken.
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+/// Reads the return address and the caller's frame pointer from the frame at `rbp`.
+/// Returns `None` if the frame isn't completely inside of a known kernel stack.
+/// # Safety
+/// Kernel stacks have to be readable. Lazily backed parts of them get backed on access.
+unsafe fn next_frame(rbp: u64) -> Option<(u64, u64)> {
+    if rbp == 0 || rbp % 8 != 0 {
+        return None;
+    }
+    let frame = VirtAddr::try_new(rbp).ok()?;
+    let stack = memory::stack::find_stack(frame)?;
+    if (frame + 16u64) > stack.top {
+        return None;
+    }
+    let frame = frame.as_ptr::<u64>();
+    unsafe { Some((frame.add(1).read(), frame.read())) }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.len == 0 {
+            return write!(f, "no frames");
+        }
+        for (i, addr) in self.frames().iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            // Return addresses point after the call, which might already be the next function.
+            let lookup = if i == 0 && self.starts_at_rip { *addr } else { addr - 1 };
+            match symbolize(lookup) {
+                Some((name, offset)) => write!(f, "#{} {}+{:#x}", i, name, offset + (addr - lookup))?,
+                None => write!(f, "#{} {:#x}", i, addr)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! Deferred work, so interrupt handlers can stay short.
+//!
+//! Interrupt handlers [`defer`] a function and an argument into a bounded lock-free queue, and kernel code outside
+//! of interrupt context calls [`run_pending`] (the main loop does) to run them with interrupts enabled. Deferring
+//! never blocks: if the queue is full the item is dropped and counted.
+//!
+//! The queue is the bounded multi-producer multi-consumer queue by Dmitry Vyukov. Every slot carries a sequence
+//! number saying whether it is free for the producer of a given position or filled for the consumer of it, so a
+//! handler interrupting another producer or the consumer can still push.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+/// The amount of items the queue can hold. Has to be a power of two.
+pub const CAPACITY: usize = 256;
+
+/// A function to run later, with its argument.
+#[derive(Debug, Clone, Copy)]
+struct WorkItem {
+    func: fn(u64),
+    arg: u64,
+}
+
+struct Slot {
+    sequence: AtomicUsize,
+    item: UnsafeCell<MaybeUninit<WorkItem>>,
+}
+
+struct WorkQueue {
+    slots: [Slot; CAPACITY],
+    /// The next position to push to.
+    tail: AtomicUsize,
+    /// The next position to pop from.
+    head: AtomicUsize,
+    queued: AtomicU64,
+    executed: AtomicU64,
+    dropped: AtomicU64,
+    max_depth: AtomicUsize,
+}
+
+// SAFETY: a slot's item is only accessed by the producer or consumer that claimed its position, see push and pop.
+unsafe impl Sync for WorkQueue {}
+
+impl WorkQueue {
+    const fn new() -> WorkQueue {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: Slot = Slot {
+            sequence: AtomicUsize::new(0),
+            item: UnsafeCell::new(MaybeUninit::uninit()),
+        };
+        let mut slots = [EMPTY; CAPACITY];
+        let mut i = 0;
+        while i < CAPACITY {
+            slots[i].sequence = AtomicUsize::new(i);
+            i += 1;
+        }
+        WorkQueue {
+            slots,
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+            queued: AtomicU64::new(0),
+            executed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            max_depth: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, item: WorkItem) -> bool {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % CAPACITY];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            // Free slots have the sequence of the position that may fill them next.
+            match (sequence as isize).wrapping_sub(pos as isize) {
+                0 => match self.tail.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: the position was claimed above, nobody else touches the slot until it is published.
+                        unsafe { (*slot.item.get()).write(item) };
+                        slot.sequence.store(pos + 1, Ordering::Release);
+                        return true;
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot still holds an item from a lap ago, the queue is full.
+                diff if diff < 0 => return false,
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<WorkItem> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % CAPACITY];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            // Filled slots have the sequence of their position plus one.
+            match (sequence as isize).wrapping_sub(pos as isize + 1) {
+                0 => match self.head.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: the slot was published by its producer, and the position was claimed above.
+                        let item = unsafe { (*slot.item.get()).assume_init() };
+                        slot.sequence.store(pos + CAPACITY, Ordering::Release);
+                        return Some(item);
+                    }
+                    Err(current) => pos = current,
+                },
+                // Not published yet, the queue is empty as far as this consumer is concerned.
+                diff if diff < 0 => return None,
+                _ => pos = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn depth(&self) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        tail.wrapping_sub(head).min(CAPACITY)
+    }
+}
+
+static QUEUE: WorkQueue = WorkQueue::new();
+
+/// Queues `func(arg)` to run outside of interrupt context. Returns false if the queue is full and it was dropped.
+/// Safe to call from interrupt handlers.
+pub fn defer(func: fn(u64), arg: u64) -> bool {
+    if !QUEUE.push(WorkItem { func, arg }) {
+        QUEUE.dropped.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    QUEUE.queued.fetch_add(1, Ordering::Relaxed);
+    QUEUE.max_depth.fetch_max(QUEUE.depth(), Ordering::Relaxed);
+    true
+}
+
+/// Runs every queued item, including ones queued while running. Returns how many ran.
+/// Has to be called from kernel context with interrupts enabled, never from an interrupt handler.
+pub fn run_pending() -> usize {
+    debug_assert!(interrupts::are_enabled(), "deferred work has to run with interrupts enabled");
+    let mut ran = 0;
+    while let Some(item) = QUEUE.pop() {
+        (item.func)(item.arg);
+        ran += 1;
+    }
+    QUEUE.executed.fetch_add(ran as u64, Ordering::Relaxed);
+    ran
+}
+
+/// Returns true if no work is queued. Items that are still being queued count as queued.
+pub fn is_empty() -> bool {
+    QUEUE.depth() == 0
+}
+
+/// Counters of the deferred work queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkQueueStats {
+    /// The amount of items waiting to run.
+    pub depth: usize,
+    /// The most items that were waiting at once.
+    pub max_depth: usize,
+    pub queued: u64,
+    pub executed: u64,
+    /// Items that didn't fit into the queue.
+    pub dropped: u64,
+}
+
+pub fn stats() -> WorkQueueStats {
+    WorkQueueStats {
+        depth: QUEUE.depth(),
+        max_depth: QUEUE.max_depth.load(Ordering::Relaxed),
+        queued: QUEUE.queued.load(Ordering::Relaxed),
+        executed: QUEUE.executed.load(Ordering::Relaxed),
+        dropped: QUEUE.dropped.load(Ordering::Relaxed),
+    }
+}
+
+impl fmt::Display for WorkQueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "deferred work: {}/{} queued (max {}), {} queued, {} run, {} dropped",
+            self.depth, CAPACITY, self.max_depth, self.queued, self.executed, self.dropped
+        )
+    }
+}
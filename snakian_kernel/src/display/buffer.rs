@@ -2,9 +2,8 @@ use core::{cmp::min, mem};
 
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
 use conquer_once::spin::OnceCell;
-use spin::Mutex;
 
-use crate::prelude::*;
+use crate::{prelude::*, sync::Mutex};
 
 use super::{vector::Vector, ColorTuple};
 /// The maximum buffer size. This is the maximum size of the buffer, and is used to prevent buffer overflows.
@@ -3,7 +3,7 @@ use core::cmp::min;
 use crate::prelude::*;
 use bootloader_api::info::{FrameBufferInfo, PixelFormat};
 use conquer_once::spin::OnceCell;
-use spin::Mutex;
+use crate::sync::IrqMutex;
 
 use super::{chars, vector::Vector, CharSprite, ColorCode, ScreenChar};
 
@@ -217,14 +217,14 @@ impl CharWriter {
     }
 }
 
-pub static CHAR_WRITER: OnceCell<Mutex<CharWriter>> = OnceCell::uninit();
+pub static CHAR_WRITER: OnceCell<IrqMutex<CharWriter>> = OnceCell::uninit();
 
 pub fn init_char_writer(buf_info: FrameBufferInfo) {
     dbg!("Initializing char writer!");
     CHAR_WRITER
         .try_init_once(move || {
             dbg!("Initializing char writer container!");
-            Mutex::new(CharWriter::new(buf_info))
+            IrqMutex::new(CharWriter::new(buf_info))
         })
         .expect("Char writer already initialized!");
 }
@@ -1,7 +1,7 @@
 use core::fmt::{self, Write};
 
 use conquer_once::spin::OnceCell;
-use spin::Mutex;
+use crate::sync::IrqMutex;
 
 use crate::{dbg, lock_once, serial_println, prelude::*};
 
@@ -158,7 +158,7 @@ impl Write for TerminalWriter {
     }
 }
 
-pub static WRITER: OnceCell<Mutex<TerminalWriter>> = OnceCell::uninit();
+pub static WRITER: OnceCell<IrqMutex<TerminalWriter>> = OnceCell::uninit();
 
 pub fn init_vga() {
     serial_println!("Initializing VGA driver!");
@@ -167,7 +167,7 @@ pub fn init_vga() {
         .try_init_once(move || {
             let writer = TerminalWriter::new();
             dbg!("Initialized writer! Moving to Mutex!");
-            Mutex::new(writer)
+            IrqMutex::new(writer)
         })
         .expect("WRITER already initialized");
     dbg!("Initialized writer container!");
@@ -0,0 +1,461 @@
+//! CPU exception handling.
+//!
+//! Every exception vector points at a small assembly stub, which saves all general purpose registers next to the
+//! frame the CPU pushed and hands the whole [`ExceptionFrame`] to [`handle_exception`]. Exceptions the kernel can
+//! recover from (breakpoints, demand paged memory) return through the stub, which restores the registers.
+//! Everything else produces a report with the decoded error code, the registers and the control registers, which
+//! goes to the serial port and then to the panic screen through [`panic::panic_runner`], followed by a backtrace.
+
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    backtrace::Backtrace,
+    gdt::{IST_FAULT_INDEX, IST_MACHINE_CHECK_INDEX, IST_NMI_INDEX, IST_PAGE_FAULT_INDEX},
+    mce,
+    interrupts::hlt_loop,
+    memory::{self, fault::PageFaultDescription},
+    panic::{self, MessageBuffer},
+    serial_println,
+    watchdog::{self, NmiSource},
+};
+
+pub const DEBUG: u8 = 1;
+pub const NMI: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const MACHINE_CHECK: u8 = 18;
+
+/// The size of each stub, they are laid out one after the other so the vector can be used as an index.
+const STUB_SIZE: u64 = 16;
+
+// Each stub pushes a zero error code if the CPU doesn't push one, then the vector, so the frame looks the same for
+// every exception. The CPU aligns the stack to 16 bytes before pushing its frame, and 22 more quad words keep it
+// aligned for the call.
+global_asm!(
+    r#"
+.macro exception_stub vector, has_error_code
+    .balign 16
+    .if \has_error_code == 0
+        push 0
+    .endif
+    push \vector
+    jmp snakian_exception_common
+.endm
+
+.pushsection .text.exceptions, "ax"
+.balign 16
+.global snakian_exception_stubs
+snakian_exception_stubs:
+    exception_stub 0, 0
+    exception_stub 1, 0
+    exception_stub 2, 0
+    exception_stub 3, 0
+    exception_stub 4, 0
+    exception_stub 5, 0
+    exception_stub 6, 0
+    exception_stub 7, 0
+    exception_stub 8, 1
+    exception_stub 9, 0
+    exception_stub 10, 1
+    exception_stub 11, 1
+    exception_stub 12, 1
+    exception_stub 13, 1
+    exception_stub 14, 1
+    exception_stub 15, 0
+    exception_stub 16, 0
+    exception_stub 17, 1
+    exception_stub 18, 0
+    exception_stub 19, 0
+    exception_stub 20, 0
+    exception_stub 21, 1
+    exception_stub 22, 0
+    exception_stub 23, 0
+    exception_stub 24, 0
+    exception_stub 25, 0
+    exception_stub 26, 0
+    exception_stub 27, 0
+    exception_stub 28, 0
+    exception_stub 29, 1
+    exception_stub 30, 1
+    exception_stub 31, 0
+
+snakian_exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call {handler}
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+.popsection
+"#,
+    handler = sym handle_exception,
+);
+
+extern "C" {
+    fn snakian_exception_stubs();
+}
+
+/// The state of the CPU when an exception happened, as saved by the exception stubs.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// The error code the CPU pushed, or 0 for exceptions without one.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Returns the name and mnemonic of an exception vector.
+pub fn exception_name(vector: u8) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("Divide Error", "#DE"),
+        1 => ("Debug", "#DB"),
+        2 => ("Non-Maskable Interrupt", "NMI"),
+        3 => ("Breakpoint", "#BP"),
+        4 => ("Overflow", "#OF"),
+        5 => ("Bound Range Exceeded", "#BR"),
+        6 => ("Invalid Opcode", "#UD"),
+        7 => ("Device Not Available", "#NM"),
+        8 => ("Double Fault", "#DF"),
+        9 => ("Coprocessor Segment Overrun", "#CSO"),
+        10 => ("Invalid TSS", "#TS"),
+        11 => ("Segment Not Present", "#NP"),
+        12 => ("Stack-Segment Fault", "#SS"),
+        13 => ("General Protection Fault", "#GP"),
+        14 => ("Page Fault", "#PF"),
+        16 => ("x87 Floating-Point Exception", "#MF"),
+        17 => ("Alignment Check", "#AC"),
+        18 => ("Machine Check", "#MC"),
+        19 => ("SIMD Floating-Point Exception", "#XM"),
+        20 => ("Virtualization Exception", "#VE"),
+        21 => ("Control Protection Exception", "#CP"),
+        28 => ("Hypervisor Injection Exception", "#HV"),
+        29 => ("VMM Communication Exception", "#VC"),
+        30 => ("Security Exception", "#SX"),
+        _ => ("Reserved", "-"),
+    }
+}
+
+fn stub_addr(vector: u8) -> VirtAddr {
+    VirtAddr::new(snakian_exception_stubs as usize as u64 + vector as u64 * STUB_SIZE)
+}
+
+macro_rules! set_stubs {
+    ($idt: expr, $($name: ident = $vector: expr),* $(,)?) => {
+        // SAFETY: every stub ends in `iretq` and works with or without an error code on the stack.
+        $(unsafe { $idt.$name.set_handler_addr(stub_addr($vector)) };)*
+    };
+}
+
+/// Points every exception entry of the IDT at the exception stubs.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    set_stubs!(
+        idt,
+        divide_error = 0,
+        debug = DEBUG,
+        breakpoint = BREAKPOINT,
+        overflow = 4,
+        bound_range_exceeded = 5,
+        invalid_opcode = 6,
+        device_not_available = 7,
+        invalid_tss = INVALID_TSS,
+        segment_not_present = SEGMENT_NOT_PRESENT,
+        stack_segment_fault = STACK_SEGMENT_FAULT,
+        general_protection_fault = GENERAL_PROTECTION_FAULT,
+        x87_floating_point = 16,
+        alignment_check = 17,
+        simd_floating_point = 19,
+        virtualization = 20,
+        cp_protection_exception = 21,
+        hv_injection_exception = 28,
+        vmm_communication_exception = 29,
+        security_exception = 30,
+    );
+    // SAFETY: the IST indices are set up by gdt::init_gdt before interrupts are enabled.
+    unsafe {
+        idt.double_fault
+            .set_handler_addr(stub_addr(DOUBLE_FAULT))
+            .set_stack_index(IST_FAULT_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub_addr(NMI))
+            .set_stack_index(IST_NMI_INDEX);
+        idt.machine_check
+            .set_handler_addr(stub_addr(MACHINE_CHECK))
+            .set_stack_index(IST_MACHINE_CHECK_INDEX);
+        idt.page_fault
+            .set_handler_addr(stub_addr(PAGE_FAULT))
+            .set_stack_index(IST_PAGE_FAULT_INDEX);
+    }
+}
+
+/// Called by the exception stubs. Returning resumes the interrupted code with the (possibly modified) frame.
+extern "C" fn handle_exception(frame: &mut ExceptionFrame) {
+    match frame.vector as u8 {
+        // Debug traps and breakpoints only report where they hit.
+        DEBUG | BREAKPOINT => serial_println!("{}", Report::new(frame, None)),
+        NMI => nmi(frame),
+        PAGE_FAULT => page_fault(frame),
+        MACHINE_CHECK => {
+            let check = mce::handle_machine_check();
+            if !check.is_recoverable() {
+                fatal(frame, Some(&check));
+            }
+        }
+        DOUBLE_FAULT => {
+            // Page faults have a stack of their own, so an overflow normally shows up there. It still ends up here
+            // when delivering another exception, like a general protection fault, runs into the guard page.
+            let rsp = VirtAddr::new_truncate(frame.rsp);
+            let stack = memory::stack::find_guard_hit(VirtAddr::new_truncate(Cr2::read_raw()))
+                .or_else(|| memory::stack::find_guard_hit(rsp));
+            match stack {
+                Some(stack) => fatal(frame, Some(&format_args!("stack overflow on {} stack", stack.name))),
+                None => fatal(frame, None),
+            }
+        }
+        _ => fatal(frame, None),
+    }
+}
+
+/// NMIs are not caused by the code they interrupt, so there is nothing to fix up, only something to report.
+fn nmi(frame: &ExceptionFrame) {
+    match watchdog::handle_nmi() {
+        NmiSource::Watchdog => {}
+        NmiSource::Lockup(lockup) => {
+            serial_println!("{}", Report::new(frame, Some(&lockup)));
+            serial_println!("Backtrace:\n{}", Backtrace::from_registers(frame.rip, frame.rbp));
+        }
+        NmiSource::Other => serial_println!("{}", Report::new(frame, None)),
+    }
+}
+
+/// Set while the page fault handler runs. The page fault stack is reset to its top on every fault, so a fault in
+/// the handler itself overwrites the frames of the outer one, which can't be resumed anymore.
+static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
+fn page_fault(frame: &ExceptionFrame) {
+    if IN_PAGE_FAULT.swap(true, Ordering::Relaxed) {
+        fatal(frame, Some(&"page fault inside of the page fault handler"));
+    }
+    let addr = VirtAddr::new_truncate(Cr2::read_raw());
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    if let Some(stack) = memory::stack::find_guard_hit(addr) {
+        fatal(frame, Some(&format_args!("stack overflow on {} stack", stack.name)));
+    }
+    // Accesses to lazily backed memory are resolved here, and the faulting instruction is restarted.
+    if let Err(reason) = memory::fault::handle_page_fault(addr, error_code) {
+        memory::dump::dump_address(addr);
+        fatal(frame, Some(&format_args!("access to {:#x}: {}", addr.as_u64(), reason)));
+    }
+    IN_PAGE_FAULT.store(false, Ordering::Relaxed);
+}
+
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Reports an exception the kernel can't recover from and shows it on the panic screen.
+fn fatal(frame: &ExceptionFrame, note: Option<&dyn fmt::Display>) -> ! {
+    if REPORTING.swap(true, Ordering::SeqCst) {
+        // Something in the reporting path faulted as well, showing it again would just fault again.
+        let (name, _) = exception_name(frame.vector as u8);
+        serial_println!("EXCEPTION: {} at {:#x} while reporting an exception", name, frame.rip);
+        interrupts::disable();
+        hlt_loop();
+    }
+    let report = Report::new(frame, note);
+    serial_println!("{}", report);
+    let backtrace = Backtrace::from_registers(frame.rip, frame.rbp);
+    serial_println!("Backtrace:\n{}", backtrace);
+
+    let mut message = MessageBuffer::<2048>::new();
+    let _ = write!(message, "{}\n{}", report, backtrace);
+    let mut location = MessageBuffer::<32>::new();
+    let _ = write!(location, "rip {:#x}", frame.rip);
+    panic::panic_runner(location.as_str(), message.as_str())
+}
+
+/// Decodes the error code of an exception.
+pub struct ErrorCodeDescription {
+    pub vector: u8,
+    pub error_code: u64,
+}
+
+impl fmt::Display for ErrorCodeDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.error_code;
+        match self.vector {
+            PAGE_FAULT => {
+                let flags = PageFaultErrorCode::from_bits_truncate(code);
+                let bits = [
+                    (PageFaultErrorCode::PROTECTION_VIOLATION, "P"),
+                    (PageFaultErrorCode::CAUSED_BY_WRITE, "W"),
+                    (PageFaultErrorCode::USER_MODE, "U"),
+                    (PageFaultErrorCode::MALFORMED_TABLE, "R"),
+                    (PageFaultErrorCode::INSTRUCTION_FETCH, "I"),
+                ];
+                write!(f, "[")?;
+                for (bit, name) in bits {
+                    write!(f, "{}", if flags.contains(bit) { name } else { "-" })?;
+                }
+                write!(f, "] {}", PageFaultDescription(flags))
+            }
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                if code == 0 {
+                    return write!(f, "not segment related");
+                }
+                // Bit 1 says the index is into the IDT, otherwise bit 2 picks the LDT over the GDT.
+                let table = if code & 0b10 != 0 {
+                    "IDT"
+                } else if code & 0b100 != 0 {
+                    "LDT"
+                } else {
+                    "GDT"
+                };
+                write!(f, "{} index {}", table, (code >> 3) & 0x1fff)?;
+                if code & 1 != 0 {
+                    write!(f, ", external event")?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "no details"),
+        }
+    }
+}
+
+/// Everything known about an exception, formatted for the serial port and the panic screen.
+struct Report<'a> {
+    frame: &'a ExceptionFrame,
+    note: Option<&'a dyn fmt::Display>,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl<'a> Report<'a> {
+    fn new(frame: &'a ExceptionFrame, note: Option<&'a dyn fmt::Display>) -> Report<'a> {
+        let (cr3_frame, cr3_flags) = Cr3::read_raw();
+        Report {
+            frame,
+            note,
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: cr3_frame.start_address().as_u64() | cr3_flags as u64,
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.frame;
+        let vector = frame.vector as u8;
+        let (name, mnemonic) = exception_name(vector);
+        writeln!(f, "EXCEPTION: {} ({}, vector {})", name, mnemonic, vector)?;
+        writeln!(
+            f,
+            "error code {:#x}: {}",
+            frame.error_code,
+            ErrorCodeDescription {
+                vector,
+                error_code: frame.error_code
+            }
+        )?;
+        if let Some(note) = self.note {
+            writeln!(f, "{}", note)?;
+        }
+        writeln!(
+            f,
+            "rip {:#018x} cs {:#x} rflags {:#x}",
+            frame.rip, frame.cs, frame.rflags
+        )?;
+        writeln!(f, "rsp {:#018x} ss {:#x}", frame.rsp, frame.ss)?;
+        let registers = [
+            ("rax", frame.rax),
+            ("rbx", frame.rbx),
+            ("rcx", frame.rcx),
+            ("rdx", frame.rdx),
+            ("rsi", frame.rsi),
+            ("rdi", frame.rdi),
+            ("rbp", frame.rbp),
+            ("r8 ", frame.r8),
+            ("r9 ", frame.r9),
+            ("r10", frame.r10),
+            ("r11", frame.r11),
+            ("r12", frame.r12),
+            ("r13", frame.r13),
+            ("r14", frame.r14),
+            ("r15", frame.r15),
+        ];
+        for line in registers.chunks(3) {
+            for (name, value) in line {
+                write!(f, "{} {:#018x}  ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "cr0 {:#018x}  cr2 {:#018x}", self.cr0, self.cr2)?;
+        write!(f, "cr3 {:#018x}  cr4 {:#018x}", self.cr3, self.cr4)
+    }
+}
@@ -1,5 +1,3 @@
-use core::ptr::addr_of;
-
 use lazy_static::lazy_static;
 use x86_64::{
     instructions::tables::load_tss,
@@ -8,21 +6,44 @@ use x86_64::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
     },
-    VirtAddr,
 };
 
+use crate::memory;
+
 pub const IST_FAULT_INDEX: u16 = 0;
+pub const IST_NMI_INDEX: u16 = 1;
+pub const IST_MACHINE_CHECK_INDEX: u16 = 2;
+pub const IST_PAGE_FAULT_INDEX: u16 = 3;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[IST_FAULT_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
+            const STACK_SIZE: u64 = 4096 * 5;
+            // The stack gets its own region with a guard page, so an overflow in the double fault handler is caught too.
+            let stack = memory::stack::allocate_stack("double fault", STACK_SIZE)
+                .expect("Failed to allocate the double fault stack");
+            stack.top
+        };
+        // NMIs can arrive in the middle of anything, including code running on a broken stack.
+        tss.interrupt_stack_table[IST_NMI_INDEX as usize] = {
+            const STACK_SIZE: u64 = 4096 * 5;
+            let stack = memory::stack::allocate_stack("nmi", STACK_SIZE).expect("Failed to allocate the NMI stack");
+            stack.top
+        };
+        // Machine checks can't trust the stack of the code they interrupt either.
+        tss.interrupt_stack_table[IST_MACHINE_CHECK_INDEX as usize] = {
+            const STACK_SIZE: u64 = 4096 * 5;
+            let stack = memory::stack::allocate_stack("machine check", STACK_SIZE)
+                .expect("Failed to allocate the machine check stack");
+            stack.top
+        };
+        // Lazily backed stacks grow through page faults, which can't push their frame onto the page that is missing.
+        tss.interrupt_stack_table[IST_PAGE_FAULT_INDEX as usize] = {
+            const STACK_SIZE: u64 = 4096 * 5;
+            let stack = memory::stack::allocate_stack("page fault", STACK_SIZE)
+                .expect("Failed to allocate the page fault stack");
+            stack.top
         };
         tss
     };
@@ -1,12 +1,9 @@
-use x86_64::structures::idt::InterruptStackFrame;
-
 use crate::{
     interrupts::{IDT_LOADER, PIC_1_OFFSET},
+    irq::{self, IrqContext, IrqReturn},
     print,
 };
 
-use self::timer::TICKS;
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -25,76 +22,50 @@ impl InterruptIndex {
 }
 
 pub mod timer {
-    use spin::Mutex;
-
-    use crate::{interrupts::PICS, println};
+    use core::sync::atomic::{AtomicU64, Ordering};
 
     use super::*;
 
-    pub static TICKS: Mutex<u64> = Mutex::new(0);
-
-    pub static mut TICKS_UNSAFE: u64 = 0;
+    /// Timer interrupts since boot. See [`time`](crate::time) for how long they are.
+    static TICKS: AtomicU64 = AtomicU64::new(0);
 
-    pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-        unsafe {
-            // HACK: force_unlock is unsafe, but we're using it here to avoid a deadlock
-            // In the future, we should probably figure out a better way to do this
-            TICKS.force_unlock();
-            *TICKS.lock() += 1;
-            TICKS_UNSAFE += 1;
-            PICS.lock()
-                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-        }
+    pub fn timer_interrupt_handler(_context: &IrqContext) -> IrqReturn {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::Handled
     }
-    /// Sleeps for a given amount of ticks. This is a busy wait. (TODO: determine how long timer ticks are)
-    #[macro_export]
-    macro_rules! sleep {
-        ($duration: expr) => {
-            let start = $crate::hardware_interrupts::timer::TICKS.lock().clone();
-            while $crate::hardware_interrupts::timer::TICKS.lock().clone() < start + $duration {}
-        };
+
+    /// Returns the amount of timer interrupts since boot. Safe to call from any context.
+    pub fn ticks() -> u64 {
+        TICKS.load(Ordering::Relaxed)
     }
 }
 
 pub mod keyboard {
-    use pc_keyboard::{layouts, Keyboard, ScancodeSet1};
-    use spin::Mutex;
     use x86_64::instructions::port::{Port, ReadOnlyAccess};
 
-    use crate::{interrupts::PICS, keyboard_driver::KEYBOARD_DRIVER};
-    use lazy_static::lazy_static;
+    use crate::{deferred, keyboard_driver::KEYBOARD_DRIVER};
 
     use super::*;
 
-    lazy_static! {
-        pub static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                pc_keyboard::HandleControl::Ignore
-            ));
-    }
-
-    pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    pub fn keyboard_interrupt_handler(_context: &IrqContext) -> IrqReturn {
         let mut port = Port::new(0x60);
         let scancode: u8 = unsafe { port.read() };
-        unsafe {
-            KEYBOARD_DRIVER.force_unlock();
-        }
+        // Decoding the scancode takes the driver lock, so it happens later outside of the interrupt handler.
+        deferred::defer(handle_scancode, scancode as u64);
+        IrqReturn::Handled
+    }
 
-        KEYBOARD_DRIVER.lock().handle_byte(scancode);
-        unsafe {
-            PICS.lock()
-                .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-        }
+    fn handle_scancode(scancode: u64) {
+        KEYBOARD_DRIVER.lock().handle_byte(scancode as u8);
     }
 }
 pub fn init_hardware() {
+    irq::register(InterruptIndex::Timer.as_u8(), "timer", timer::timer_interrupt_handler)
+        .expect("timer vector is free");
+    irq::register(InterruptIndex::Keyboard.as_u8(), "keyboard", keyboard::keyboard_interrupt_handler)
+        .expect("keyboard vector is free");
+    // Spurious interrupts must not be acknowledged, so they bypass the dispatcher.
     IDT_LOADER
         .lock()
-        .add_raw(InterruptIndex::Timer, timer::timer_interrupt_handler);
-    IDT_LOADER.lock().add_raw(
-        InterruptIndex::Keyboard,
-        keyboard::keyboard_interrupt_handler,
-    );
+        .add_raw_unchecked(crate::apic::SPURIOUS_VECTOR, crate::apic::spurious_interrupt_handler);
 }
@@ -1,84 +1,30 @@
-use crate::{dbg, gdt::IST_FAULT_INDEX, hardware_interrupts::InterruptIndex, lock_once, println};
+use crate::{
+    apic, dbg, exceptions,
+    hardware_interrupts::InterruptIndex,
+    irq,
+    sync::{IrqMutex, Mutex},
+};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Once;
 use x86_64::{
-    instructions::hlt,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    instructions::port::Port,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
 };
 
-macro_rules! def_handler_isf {
-    ($idt: expr, $name: ident) => {
-        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
-            crate::serial_println!("EXCEPTION: {}\n{:#?}", stringify!($name), stack_frame);
-        }
-        $idt.$name.set_handler_fn($name);
-    };
-}
-
-macro_rules! def_handler_isf_code {
-    ($idt: expr,$name: ident) => {
-        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) {
-            crate::serial_println!(
-                "EXCEPTION: {} ({})\n{:#?}",
-                stringify!($name),
-                error_code,
-                stack_frame
-            );
-        }
-        $idt.$name.set_handler_fn($name);
-    };
-
-    ($idt: expr,$name: ident, $_trap: expr) => {
-        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
-            crate::serial_println!(
-                "EXCEPTION: {} ({})\n{:#?}",
-                stringify!($name),
-                error_code,
-                stack_frame
-            );
-            hlt_loop();
-        }
-        $idt.$name.set_handler_fn($name);
-    };
-}
-
 lazy_static! {
-    pub static ref IDT_LOADER: spin::Mutex<IdtLoader> = spin::Mutex::new(IdtLoader::new());
+    pub static ref IDT_LOADER: Mutex<IdtLoader> = Mutex::new(IdtLoader::new());
     static ref IDT: Once<InterruptDescriptorTable> = Once::new();
 }
 
 /// Initializes the IDT. This function should be called before any interrupts are enabled, and after all the handlers are added.
+/// Handlers added through [`irq`] can be registered at any time.
 pub fn init_idt() {
     dbg!("Initializing IDT");
     let mut idt = InterruptDescriptorTable::new();
 
-    x86_64::set_general_handler!(&mut idt, general_handler);
-
-    def_handler_isf_code!(idt, general_protection_fault);
-
-    def_handler_isf!(idt, breakpoint);
-
-    def_handler_isf_code!(idt, double_fault, "no return");
-
-    extern "x86-interrupt" fn page_fault_handler(
-        stack_frame: InterruptStackFrame,
-        error_code: PageFaultErrorCode,
-    ) {
-        use x86_64::registers::control::Cr2;
-
-        println!("EXCEPTION: PAGE FAULT");
-        println!("Accessed Address: {:?}", Cr2::read());
-        println!("Error Code: {:?}", error_code);
-        println!("{:#?}", stack_frame);
-        panic!(
-            "Page Fault ({:?}:{:?})! {:#?}",
-            error_code,
-            Cr2::read(),
-            stack_frame
-        );
-    }
-    idt.page_fault.set_handler_fn(page_fault_handler);
+    exceptions::install(&mut idt);
+    x86_64::set_general_handler!(&mut idt, irq_handler, irq::FIRST_VECTOR..=255);
 
     let mut lock = IDT_LOADER.lock();
     lock.load(&mut idt);
@@ -87,19 +33,62 @@ pub fn init_idt() {
     unsafe { PICS.lock().initialize() };
 }
 
-fn general_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
-    if let Some(code) = error_code {
-        crate::serial_println!("EXCEPTION: {} ({})\n{:#?}", index, code, stack_frame);
-    } else {
-        crate::serial_println!("EXCEPTION: {}\n{:#?}", index, stack_frame);
-    }
+fn irq_handler(stack_frame: InterruptStackFrame, index: u8, _error_code: Option<u64>) {
+    irq::dispatch(index, &stack_frame);
 }
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+/// OCW3 command that makes the next read of the command port return the in-service register.
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_EOI: u8 = 0x20;
+
+/// Interrupt handlers acknowledge interrupts through the PICs, so they keep interrupts disabled while locked.
+pub static PICS: IrqMutex<ChainedPics> = IrqMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Checks whether an interrupt on the lowest priority line of a PIC (IRQ 7 or 15) is spurious, by looking at the
+/// in-service register. The master PIC doesn't get an end of interrupt for a spurious IRQ 7. A spurious IRQ 15 still
+/// went through the cascade line of the master, so the master gets one, but the slave doesn't.
+pub fn is_spurious_pic_interrupt(vector: u8) -> bool {
+    if apic::is_enabled() {
+        return false;
+    }
+    let command = match vector {
+        v if v == PIC_1_OFFSET + 7 => PIC_1_COMMAND,
+        v if v == PIC_2_OFFSET + 7 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    let _pics = PICS.lock();
+    // SAFETY: reading the in-service register doesn't change the state of the PICs.
+    unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(PIC_READ_ISR);
+        if port.read() & (1 << 7) != 0 {
+            return false;
+        }
+        if command == PIC_2_COMMAND {
+            Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI);
+        }
+    }
+    true
+}
+
+/// Signals the end of an interrupt to whichever interrupt controller is active.
+pub fn notify_end_of_interrupt(index: InterruptIndex) {
+    end_of_interrupt(index.as_u8());
+}
+
+/// Signals the end of the interrupt on `vector` to whichever interrupt controller is active.
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
+}
 
 type HandlerFn = extern "x86-interrupt" fn(InterruptStackFrame);
 
@@ -168,14 +157,14 @@ impl IdtLoader {
     /// This function panics if the IDT is already loaded.
     pub fn add_handler(&mut self, handler: InterruptHandler) {
         if self.is_loaded {
-            panic!("Cannot add handler after IDT is loaded!");
+            panic!("Cannot add handler after IDT is loaded, use irq::register instead!");
         }
         self.handlers[handler.index as usize] = Some(handler);
     }
     /// Adds a new constructed handler to the IDT without checking if the index is valid
     pub fn add_raw_unchecked(&mut self, index: u8, handler: HandlerFn) {
         if self.is_loaded {
-            panic!("Cannot add handler after IDT is loaded!");
+            panic!("Cannot add handler after IDT is loaded, use irq::register instead!");
         }
         self.handlers[index as usize] =
             Some(unsafe { InterruptHandler::new_unchecked(index, handler) });
@@ -183,7 +172,7 @@ impl IdtLoader {
     /// Adds a new handler to the IDT constructed from the index and handler
     pub fn add_raw(&mut self, index: InterruptIndex, handler: HandlerFn) {
         if self.is_loaded {
-            panic!("Cannot add handler after IDT is loaded!");
+            panic!("Cannot add handler after IDT is loaded, use irq::register instead!");
         }
         self.handlers[index.as_usize()] = Some(InterruptHandler::new(index, handler));
     }
@@ -191,7 +180,7 @@ impl IdtLoader {
     pub fn add_handler_fn(&mut self, fun: fn(&mut InterruptDescriptorTable)) {
         assert!(self.fndex < 256, "Cannot add more than 256 handlers!");
         if self.is_loaded {
-            panic!("Cannot add handler after IDT is loaded!");
+            panic!("Cannot add handler after IDT is loaded, use irq::register instead!");
         }
         self.loader_fns[self.fndex] = Some(fun);
         self.fndex += 1;
@@ -0,0 +1,271 @@
+//! Runtime interrupt dispatch.
+//!
+//! [`interrupts::init_idt`](crate::interrupts::init_idt) installs a generic stub on every vector from
+//! [`FIRST_VECTOR`] up, which calls [`dispatch`]. Drivers [`register`] handlers for a vector at any time, before or
+//! after the IDT is loaded, and remove them again with [`unregister`]. A handler is a closure, so it can carry
+//! whatever context the driver needs.
+//!
+//! A vector can be shared by several handlers if all of them are registered with [`register_shared`]. Every handler
+//! of a shared vector is called, and reports through [`IrqReturn`] whether its device raised the interrupt.
+//! The end of the interrupt is signalled by [`dispatch`] once all handlers ran, so handlers must not do it themselves.
+//!
+//! Handlers for ISA vectors (`PIC_1_OFFSET + irq`) unmask their IRQ at the I/O APIC when the first one is registered,
+//! and mask it again when the last one is removed.
+//!
+//! Handlers run with interrupts disabled and must not register or unregister handlers themselves.
+//!
+//! Every vector counts how often it fired, and how many of those were spurious. [`InterruptTable`] shows the counts
+//! in the style of `/proc/interrupts`.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{
+    apic,
+    interrupts::{self, PIC_1_OFFSET},
+    prelude::*,
+    sync::IrqMutex,
+};
+
+/// The first vector handled by the dispatcher. Everything below is a CPU exception.
+pub const FIRST_VECTOR: u8 = 32;
+/// The amount of vectors handled by the dispatcher.
+const VECTORS: usize = 256 - FIRST_VECTOR as usize;
+/// The amount of ISA IRQs, which are routed to the vectors starting at `PIC_1_OFFSET`.
+const ISA_IRQS: u8 = 16;
+
+/// What a handler did with an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt came from this handler's device and was dealt with.
+    Handled,
+    /// The interrupt wasn't for this handler, which happens on shared vectors.
+    NotHandled,
+}
+
+/// What a handler gets to see of the interrupt it is called for.
+#[derive(Debug)]
+pub struct IrqContext<'a> {
+    pub vector: u8,
+    pub stack_frame: &'a InterruptStackFrame,
+}
+
+/// The type of an interrupt handler.
+pub type Handler = Box<dyn Fn(&IrqContext) -> IrqReturn + Send + Sync>;
+
+/// Identifies a registered handler, see [`unregister`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u32,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The vector is a CPU exception, or reserved by the kernel.
+    InvalidVector(u8),
+    /// The vector already has a handler and one of them doesn't allow sharing.
+    Busy(u8),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrqError::InvalidVector(vector) => write!(f, "vector {} can't have an IRQ handler", vector),
+            IrqError::Busy(vector) => write!(f, "vector {} is already in use and can't be shared", vector),
+        }
+    }
+}
+
+struct Action {
+    id: u32,
+    name: &'static str,
+    shared: bool,
+    handler: Handler,
+}
+
+const NO_ACTIONS: Vec<Action> = Vec::new();
+/// The handlers of every vector, indexed from [`FIRST_VECTOR`]. Held while handlers run, which is fine because they
+/// run with interrupts disabled and can't nest.
+static ACTIONS: IrqMutex<[Vec<Action>; VECTORS]> = IrqMutex::new([NO_ACTIONS; VECTORS]);
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// How often each vector fired, not counting spurious interrupts.
+static COUNTS: [AtomicU64; 256] = [ZERO; 256];
+static SPURIOUS_COUNTS: [AtomicU64; 256] = [ZERO; 256];
+
+/// Returns the index of a vector in [`ACTIONS`], if the dispatcher handles it.
+fn index(vector: u8) -> Option<usize> {
+    vector.checked_sub(FIRST_VECTOR).map(usize::from)
+}
+
+/// Returns the ISA IRQ a vector belongs to, if it is one.
+fn isa_irq(vector: u8) -> Option<u8> {
+    vector.checked_sub(PIC_1_OFFSET).filter(|irq| *irq < ISA_IRQS)
+}
+
+/// Registers a handler that has the vector to itself.
+pub fn register(
+    vector: u8,
+    name: &'static str,
+    handler: impl Fn(&IrqContext) -> IrqReturn + Send + Sync + 'static,
+) -> Result<HandlerId, IrqError> {
+    add(vector, name, false, Box::new(handler))
+}
+
+/// Registers a handler that shares the vector with other shared handlers.
+pub fn register_shared(
+    vector: u8,
+    name: &'static str,
+    handler: impl Fn(&IrqContext) -> IrqReturn + Send + Sync + 'static,
+) -> Result<HandlerId, IrqError> {
+    add(vector, name, true, Box::new(handler))
+}
+
+fn add(vector: u8, name: &'static str, shared: bool, handler: Handler) -> Result<HandlerId, IrqError> {
+    let index = match index(vector) {
+        Some(index) if vector != apic::SPURIOUS_VECTOR => index,
+        _ => return Err(IrqError::InvalidVector(vector)),
+    };
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let first = {
+        let mut table = ACTIONS.lock();
+        let actions = &mut table[index];
+        if actions.iter().any(|action| !action.shared || !shared) {
+            return Err(IrqError::Busy(vector));
+        }
+        actions.push(Action {
+            id,
+            name,
+            shared,
+            handler,
+        });
+        actions.len() == 1
+    };
+    if first {
+        if let Some(irq) = isa_irq(vector) {
+            apic::set_irq_masked(irq, false);
+        }
+    }
+    debug!("Registered IRQ handler {} on vector {}", name, vector);
+    Ok(HandlerId { vector, id })
+}
+
+/// Removes a handler. Returns false if it was already removed.
+pub fn unregister(handler: HandlerId) -> bool {
+    let Some(index) = index(handler.vector) else {
+        return false;
+    };
+    let removed = {
+        let mut table = ACTIONS.lock();
+        let actions = &mut table[index];
+        actions
+            .iter()
+            .position(|action| action.id == handler.id)
+            .map(|position| (actions.remove(position), actions.is_empty()))
+    };
+    let Some((action, last)) = removed else {
+        return false;
+    };
+    if last {
+        if let Some(irq) = isa_irq(handler.vector) {
+            apic::set_irq_masked(irq, true);
+        }
+    }
+    debug!("Removed IRQ handler {} from vector {}", action.name, handler.vector);
+    true
+}
+
+/// Returns true if the vector has at least one handler.
+pub fn has_handlers(vector: u8) -> bool {
+    index(vector).map_or(false, |index| !ACTIONS.lock()[index].is_empty())
+}
+
+/// Calls `f` with the vector, name and sharing of every registered handler.
+pub fn for_each_handler(mut f: impl FnMut(u8, &'static str, bool)) {
+    for (index, actions) in ACTIONS.lock().iter().enumerate() {
+        for action in actions.iter() {
+            f(FIRST_VECTOR + index as u8, action.name, action.shared);
+        }
+    }
+}
+
+/// Returns how often a vector fired, not counting spurious interrupts.
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Returns how many spurious interrupts arrived on a vector.
+pub fn spurious_count(vector: u8) -> u64 {
+    SPURIOUS_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Counts a spurious interrupt. For interrupts that don't go through [`dispatch`], like the APIC spurious vector.
+pub fn record_spurious(vector: u8) {
+    SPURIOUS_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Runs the handlers of a vector and signals the end of the interrupt.
+/// Returns true if one of the handlers dealt with it.
+pub fn dispatch(vector: u8, stack_frame: &InterruptStackFrame) -> bool {
+    // Spurious PIC interrupts have to skip the handlers, and the end of interrupt is taken care of already.
+    if interrupts::is_spurious_pic_interrupt(vector) {
+        record_spurious(vector);
+        return false;
+    }
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+    let context = IrqContext { vector, stack_frame };
+    let mut handled = false;
+    if let Some(index) = index(vector) {
+        for action in ACTIONS.lock()[index].iter() {
+            handled |= (action.handler)(&context) == IrqReturn::Handled;
+        }
+    }
+    interrupts::end_of_interrupt(vector);
+    handled
+}
+
+/// Renders the interrupt counts like `/proc/interrupts`: one row for every vector that has handlers or fired.
+pub struct InterruptTable;
+
+impl fmt::Display for InterruptTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>6} {:>12} {:>9}  {:<8} handlers", "vector", "count", "spurious", "chip")?;
+        for vector in FIRST_VECTOR..=u8::MAX {
+            let (count, spurious) = (count(vector), spurious_count(vector));
+            if count == 0 && spurious == 0 && !has_handlers(vector) && vector != apic::SPURIOUS_VECTOR {
+                continue;
+            }
+            let chip = match isa_irq(vector) {
+                _ if vector == apic::SPURIOUS_VECTOR => "LAPIC",
+                Some(_) if apic::is_enabled() => "IO-APIC",
+                Some(_) => "XT-PIC",
+                None => "-",
+            };
+            write!(f, "\n{:>6} {:>12} {:>9}  {:<8}", vector, count, spurious, chip)?;
+            if vector == apic::SPURIOUS_VECTOR {
+                write!(f, " spurious")?;
+                continue;
+            }
+            let Some(index) = index(vector) else {
+                continue;
+            };
+            for (i, action) in ACTIONS.lock()[index].iter().enumerate() {
+                write!(f, "{}{}", if i == 0 { " " } else { ", " }, action.name)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,9 +2,8 @@
 
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1};
-use spin::Mutex;
 
-use crate::println;
+use crate::sync::Mutex;
 
 lazy_static! {
     pub static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
@@ -1,34 +1,48 @@
 #![no_std]
 #![no_main]
-#![feature(panic_info_message, custom_test_frameworks, abi_x86_interrupt)]
+#![feature(panic_info_message, custom_test_frameworks, abi_x86_interrupt, alloc_error_handler)]
 #![test_runner(crate::testing::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 // make it a compiler err becuase bad practice
 #![deny(unsafe_op_in_unsafe_fn)]
 
+extern crate alloc;
+
 use core::{fmt::Write, mem, panic::PanicInfo};
 
 use bootloader_api::{config::Mapping, entry_point, info::FrameBuffer, BootInfo, BootloaderConfig};
 use hardware_interrupts::init_hardware;
 use ::log::Level;
-use spin::Mutex;
 use x86_64::{
     instructions::{hlt, interrupts::without_interrupts},
     VirtAddr,
 };
 
-use crate::{display::ColorCode, hardware_interrupts::timer::TICKS_UNSAFE, prelude::*};
+use crate::{prelude::*, sync::Mutex};
 
+pub mod acpi;
+pub mod apic;
+pub mod backtrace;
+pub mod deferred;
 pub mod display;
+pub mod exceptions;
 pub mod gdt;
 pub mod hardware_interrupts;
 pub mod interrupts;
+pub mod irq;
 pub mod keyboard_driver;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod mce;
 pub mod memory;
 pub mod serial;
+pub mod sync;
 pub mod testing;
 pub mod log;
 pub mod panic;
+pub mod power;
+pub mod time;
+pub mod watchdog;
 
 #[macro_export]
 /// Prints out to the serial port with the file and line number
@@ -80,6 +94,12 @@ fn panic(info: &PanicInfo) -> ! {
     testing::panic_handler(info);
 }
 
+/// Called when the global allocator fails to allocate. Panicking here routes the error through the panic screen.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("Heap allocation failed: {:?}", layout)
+}
+
 pub static BOOT_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
@@ -95,8 +115,25 @@ pub fn init(boot_info: &'static mut bootloader_api::BootInfo) {
     #[cfg(not(debug_assertions))]
     log::init_logger(Level::Trace, Level::Warn);
     info!("Initializing hardware");
+    backtrace::init(boot_info);
     info!("Initializing memory");
-    unsafe { memory::init(boot_info.physical_memory_offset.into_option().unwrap()) };
+    unsafe { memory::init(boot_info) };
+    let frames = memory::frame_stats();
+    info!(
+        "Physical memory: {} free frames, {} used, {} bootloader, {} reserved",
+        frames.free, frames.used, frames.bootloader, frames.reserved
+    );
+    info!("Kernel heap using the {} allocator", memory::heap::ALLOCATOR_NAME);
+    memory::stack::register_boot_stack(BOOT_CONFIG.kernel_stack_size);
+    // Exceptions need the IST stacks and the IDT before anything else can fault.
+    gdt::init_gdt();
+    info!("Initialized GDT");
+    init_hardware();
+    interrupts::init_idt();
+    info!("Initialized IDT");
+    info!("Hardening kernel memory");
+    unsafe { memory::hardening::harden(boot_info) };
+    acpi::init(boot_info.rsdp_addr.into_option());
     info!("Initializing VGA driver");
     let framebuf = boot_info.framebuffer.as_mut().unwrap();
     info!("Framebuffer address: {:p}", framebuf);
@@ -105,22 +142,30 @@ pub fn init(boot_info: &'static mut bootloader_api::BootInfo) {
     info!("Starting display logging");
     log::init_display_logger();
     info!("Initialized display logging");
-    init_hardware();
-    interrupts::init_idt();
-    info!("Initialized IDT");
-    gdt::init_gdt();
-    info!("Initialized GDT");
+    mce::init();
+    apic::init();
+    time::init();
+    watchdog::init();
     info!("Enabling interrupts");
     x86_64::instructions::interrupts::enable();
     info!("Enabled interrupts");
     info!("Initialized hardware");
+    memory::stack::log_stack_usage();
     *HAS_INIT.lock() = true;
 }
 /// Contains several useful functions to be included in the prelude
-// TODO: when alloc is implemented, add stuff like Vec, Box, etc (like pub use alloc::vec::Vec; etc)
 pub mod prelude {
     pub use crate::{
         dbg, eprint, eprintln, lock_once, print, println, serial_print, serial_println,
     };
+    pub use alloc::{
+        boxed::Box,
+        collections::BTreeMap,
+        format,
+        string::{String, ToString},
+        sync::Arc,
+        vec,
+        vec::Vec,
+    };
     pub use log::{debug, error, info, trace, warn};
 }
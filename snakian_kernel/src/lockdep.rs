@@ -0,0 +1,369 @@
+//! Lock dependency validator, enabled with the `lockdep` feature.
+//!
+//! Every [`sync::Mutex`](crate::sync::Mutex) and [`IrqMutex`](crate::sync::IrqMutex) is its own lock class. Whenever
+//! a lock is taken while others are held, the validator records that the held classes come before the new one.
+//! Taking a lock that, directly or through other locks, was taken while the new one's predecessors were held is an
+//! inversion: two paths through the kernel doing that at the same time would deadlock. The first inversion panics
+//! with the call sites of both orders, before the lock is actually taken, so it shows up as a failed test instead of
+//! a hang. Locking a lock that is already held is reported the same way.
+//!
+//! Everything is kept in fixed size tables, the validator never allocates. If they run out it turns itself off.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::prelude::*;
+
+/// The maximum amount of lock classes. Each class is one bit in the dependency rows.
+pub const MAX_CLASSES: usize = 64;
+/// The maximum amount of locks held at once.
+pub const MAX_HELD: usize = 16;
+/// The maximum amount of dependencies shown for an inversion.
+const MAX_CHAIN: usize = 8;
+
+type Site = &'static Location<'static>;
+
+#[derive(Debug, Clone, Copy)]
+struct Class {
+    /// The address of the lock.
+    key: usize,
+    name: &'static str,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    class: usize,
+    site: Site,
+}
+
+struct State {
+    classes: [Option<Class>; MAX_CLASSES],
+    class_count: usize,
+    /// Bit `b` of `after[a]` is set once class `b` was taken while `a` was held.
+    after: [u64; MAX_CLASSES],
+    /// Where each dependency was first seen: the site the first lock was taken at, and the site of the second one.
+    sites: [[Option<(Site, Site)>; MAX_CLASSES]; MAX_CLASSES],
+    dependencies: usize,
+    held: [Option<Held>; MAX_HELD],
+    held_count: usize,
+}
+
+struct Validator {
+    state: UnsafeCell<State>,
+    /// Set while the state is being looked at. Exceptions taking locks in the meantime aren't tracked.
+    busy: AtomicBool,
+    enabled: AtomicBool,
+}
+
+// SAFETY: the state is only accessed with interrupts disabled and `busy` claimed, on a single CPU.
+unsafe impl Sync for Validator {}
+
+static VALIDATOR: Validator = Validator {
+    state: UnsafeCell::new(State {
+        classes: [None; MAX_CLASSES],
+        class_count: 0,
+        after: [0; MAX_CLASSES],
+        sites: [[None; MAX_CLASSES]; MAX_CLASSES],
+        dependencies: 0,
+        held: [None; MAX_HELD],
+        held_count: 0,
+    }),
+    busy: AtomicBool::new(false),
+    enabled: AtomicBool::new(true),
+};
+
+/// Runs `f` on the state, unless the validator is disabled or already busy.
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        if !VALIDATOR.enabled.load(Ordering::Relaxed) || VALIDATOR.busy.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: `busy` was claimed above with interrupts disabled.
+        let result = f(unsafe { &mut *VALIDATOR.state.get() });
+        VALIDATOR.busy.store(false, Ordering::Release);
+        Some(result)
+    })
+}
+
+/// Turns the validator off for good. The panic handler calls this, since it takes locks from whatever state the
+/// kernel was in.
+pub fn disable() {
+    VALIDATOR.enabled.store(false, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    VALIDATOR.enabled.load(Ordering::Relaxed)
+}
+
+/// Why the validator turned itself off.
+// Only ever returned once, right before reporting. Boxing the inversion isn't an option, the validator runs inside
+// the allocator's lock and must not allocate.
+#[allow(clippy::large_enum_variant)]
+enum Failure {
+    Inversion(Inversion),
+    OutOfClasses,
+    TooManyHeld,
+}
+
+/// Records that the lock at `key` is about to be taken at `site`. Called before spinning, so an inversion is
+/// reported instead of deadlocking. Locks taken with `try_lock` can't deadlock and are only recorded as held.
+pub(crate) fn acquire(key: usize, name: &'static str, site: Site, try_lock: bool) {
+    let failure = with_state(|state| {
+        let class = state.class(key, name).ok_or(Failure::OutOfClasses)?;
+        if !try_lock {
+            if let Some(inversion) = state.check(class, site) {
+                return Err(Failure::Inversion(inversion));
+            }
+            state.add_dependencies(class, site);
+        }
+        state.push(Held { class, site }).ok_or(Failure::TooManyHeld)
+    });
+    let Some(Err(failure)) = failure else {
+        return;
+    };
+    // Reporting takes locks too, which mustn't be tracked anymore.
+    disable();
+    match failure {
+        Failure::Inversion(inversion) => panic!("{}", inversion),
+        Failure::OutOfClasses => warn!("lockdep: more than {} lock classes, turning off", MAX_CLASSES),
+        Failure::TooManyHeld => warn!("lockdep: more than {} locks held at once, turning off", MAX_HELD),
+    }
+}
+
+/// Records that the lock at `key` was released. Locks that aren't tracked, e.g. ones taken while the validator was
+/// busy, are ignored.
+pub(crate) fn release(key: usize) {
+    with_state(|state| state.pop(key));
+}
+
+impl State {
+    /// Returns the class of the lock at `key`, registering it if it's new.
+    fn class(&mut self, key: usize, name: &'static str) -> Option<usize> {
+        let classes = &self.classes[..self.class_count];
+        if let Some(index) = classes.iter().position(|class| class.is_some_and(|class| class.key == key)) {
+            return Some(index);
+        }
+        if self.class_count == MAX_CLASSES {
+            return None;
+        }
+        self.classes[self.class_count] = Some(Class { key, name });
+        self.class_count += 1;
+        Some(self.class_count - 1)
+    }
+
+    fn name(&self, class: usize) -> &'static str {
+        self.classes[class].map_or("<unknown>", |class| class.name)
+    }
+
+    fn held(&self) -> impl Iterator<Item = Held> + '_ {
+        self.held[..self.held_count].iter().flatten().copied()
+    }
+
+    /// Checks whether taking `class` while holding the current locks could deadlock.
+    fn check(&self, class: usize, site: Site) -> Option<Inversion> {
+        for held in self.held() {
+            let mut inversion = Inversion {
+                taking: (self.name(class), site),
+                holding: (self.name(held.class), held.site),
+                chain: [None; MAX_CHAIN],
+                chain_len: 0,
+            };
+            if held.class == class {
+                return Some(inversion);
+            }
+            if let Some(path) = self.path(class, held.class) {
+                for (from, to) in path.edges().take(MAX_CHAIN) {
+                    let Some((from_site, to_site)) = self.sites[from][to] else { continue };
+                    inversion.chain[inversion.chain_len] = Some(Dependency {
+                        first: (self.name(from), from_site),
+                        then: (self.name(to), to_site),
+                    });
+                    inversion.chain_len += 1;
+                }
+                return Some(inversion);
+            }
+        }
+        None
+    }
+
+    /// Finds the shortest chain of recorded dependencies leading from `from` to `to`.
+    fn path(&self, from: usize, to: usize) -> Option<Path> {
+        let mut parent = [usize::MAX; MAX_CLASSES];
+        let mut visited = 1u64 << from;
+        let mut frontier = 1u64 << from;
+        while frontier != 0 {
+            let mut next = 0;
+            for class in bits(frontier) {
+                for successor in bits(self.after[class] & !visited) {
+                    parent[successor] = class;
+                    visited |= 1 << successor;
+                    next |= 1 << successor;
+                }
+            }
+            if visited & (1 << to) != 0 {
+                return Some(Path { parent, from, to });
+            }
+            frontier = next;
+        }
+        None
+    }
+
+    /// Records that every held lock comes before `class`.
+    fn add_dependencies(&mut self, class: usize, site: Site) {
+        for index in 0..self.held_count {
+            let Some(held) = self.held[index] else { continue };
+            if self.after[held.class] & (1 << class) == 0 {
+                self.after[held.class] |= 1 << class;
+                self.sites[held.class][class] = Some((held.site, site));
+                self.dependencies += 1;
+            }
+        }
+    }
+
+    fn push(&mut self, held: Held) -> Option<()> {
+        if self.held_count == MAX_HELD {
+            return None;
+        }
+        self.held[self.held_count] = Some(held);
+        self.held_count += 1;
+        Some(())
+    }
+
+    /// Removes the most recently taken lock with the given key. Locks don't have to be released in order.
+    fn pop(&mut self, key: usize) {
+        let Some(index) = self.held[..self.held_count]
+            .iter()
+            .rposition(|held| held.is_some_and(|held| self.classes[held.class].is_some_and(|class| class.key == key)))
+        else {
+            return;
+        };
+        self.held.copy_within(index + 1..self.held_count, index);
+        self.held_count -= 1;
+        self.held[self.held_count] = None;
+    }
+}
+
+/// Iterates over the indices of the set bits.
+fn bits(mut mask: u64) -> impl Iterator<Item = usize> {
+    core::iter::from_fn(move || {
+        if mask == 0 {
+            return None;
+        }
+        let bit = mask.trailing_zeros() as usize;
+        mask &= mask - 1;
+        Some(bit)
+    })
+}
+
+struct Path {
+    parent: [usize; MAX_CLASSES],
+    from: usize,
+    to: usize,
+}
+
+impl Path {
+    /// The dependencies along the path, starting at `from`.
+    fn edges(&self) -> impl Iterator<Item = (usize, usize)> {
+        let mut classes = [0; MAX_CLASSES];
+        let mut len = 0;
+        let mut class = self.to;
+        while class != self.from {
+            classes[len] = class;
+            len += 1;
+            class = self.parent[class];
+        }
+        classes[len] = self.from;
+        classes[..=len].reverse();
+        (0..len).map(move |i| (classes[i], classes[i + 1]))
+    }
+}
+
+/// One recorded lock order.
+#[derive(Debug, Clone, Copy)]
+struct Dependency {
+    first: (&'static str, Site),
+    then: (&'static str, Site),
+}
+
+/// A lock about to be taken in the opposite order of what was recorded before.
+#[derive(Debug)]
+struct Inversion {
+    taking: (&'static str, Site),
+    holding: (&'static str, Site),
+    /// How the lock being taken was ordered before the held one. Empty if it's the same lock.
+    chain: [Option<Dependency>; MAX_CHAIN],
+    chain_len: usize,
+}
+
+impl fmt::Display for Inversion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.chain_len == 0 {
+            return write!(
+                f,
+                "lockdep: {} locked again at {}, it is already held since {}",
+                self.taking.0, self.taking.1, self.holding.1
+            );
+        }
+        writeln!(
+            f,
+            "lockdep: lock order inversion\n{} locked at {}\nwhile holding {} locked at {}\nbut before:",
+            self.taking.0, self.taking.1, self.holding.0, self.holding.1
+        )?;
+        for dependency in self.chain[..self.chain_len].iter().flatten() {
+            writeln!(
+                f,
+                "  {} locked at {}\n  then {} locked at {}",
+                dependency.first.0, dependency.first.1, dependency.then.0, dependency.then.1
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters of the lock dependency validator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockdepStats {
+    pub enabled: bool,
+    pub classes: usize,
+    /// Recorded lock orders between two classes.
+    pub dependencies: usize,
+    pub held: usize,
+}
+
+/// Returns the current counters, or `None` if the validator is busy.
+pub fn stats() -> Option<LockdepStats> {
+    let enabled = is_enabled();
+    // The state doesn't change anymore once the validator is off.
+    let read = |state: &State| LockdepStats {
+        enabled,
+        classes: state.class_count,
+        dependencies: state.dependencies,
+        held: state.held_count,
+    };
+    if enabled {
+        with_state(|state| read(state))
+    } else {
+        // SAFETY: nothing writes to the state once the validator is disabled.
+        Some(read(unsafe { &*VALIDATOR.state.get() }))
+    }
+}
+
+impl fmt::Display for LockdepStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "lockdep: {}, {}/{} classes, {} dependencies, {} locks held",
+            if self.enabled { "on" } else { "off" },
+            self.classes,
+            MAX_CLASSES,
+            self.dependencies,
+            self.held
+        )
+    }
+}
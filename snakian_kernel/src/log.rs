@@ -2,9 +2,7 @@ use core::{cell::UnsafeCell, mem::MaybeUninit};
 
 use conquer_once::spin::OnceCell;
 use log::Log;
-use spin::Mutex;
-
-use crate::{display, serial_println, prelude::*};
+use crate::{display, serial_println, prelude::*, sync::Mutex};
 
 
 
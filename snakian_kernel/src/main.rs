@@ -13,7 +13,7 @@ use pc_keyboard::KeyCode;
 use snakian_kernel::prelude::*;
 
 use snakian_kernel::{
-    dbg,
+    dbg, deferred,
     display::{self, terminal::WRITER, CHAR_WRITER},
     init,
     keyboard_driver::KEYBOARD_DRIVER,
@@ -85,6 +85,8 @@ fn os_entry_point(boot_info: &'static mut BootInfo) -> ! {
     let mut keys = [0 as u8; 128];
     let mut i = 0;
     loop {
+        // Keyboard input is decoded here, the interrupt handler only queues the scancodes.
+        deferred::run_pending();
         let lock = KEYBOARD_DRIVER.lock();
         if let Some(curchar) = lock.current_char {
             if key != Some(curchar) {
@@ -94,13 +96,48 @@ fn os_entry_point(boot_info: &'static mut BootInfo) -> ! {
                     keys[i] = 0;
                     i = i.saturating_sub(1);
                 } else if lock.current_char_as_key == Some(KeyCode::Return) {
+                    print!("\n");
                     // parse a command here. This is intended to be super quick and dirty
                     if keys.starts_with(b"shup") {
                         lock_once!(WRITER).shift_up();
+                    } else if keys.starts_with(b"heap") {
+                        println!("{} allocator", memory::heap::ALLOCATOR_NAME);
+                        println!("{}", memory::heap::heap_stats());
+                    } else if keys.starts_with(b"maps") {
+                        memory::region::dump_regions();
+                        memory::dump::dump_mappings();
+                        println!("Mappings dumped to serial");
+                    } else if keys.starts_with(b"acpi") {
+                        snakian_kernel::acpi::dump_tables();
+                        println!("ACPI tables dumped to serial");
+                    } else if keys.starts_with(b"shutdown") {
+                        snakian_kernel::power::shutdown();
+                    } else if keys.starts_with(b"reboot") {
+                        snakian_kernel::power::reboot();
+                    } else if keys.starts_with(b"deferred") {
+                        println!("{}", deferred::stats());
+                    } else if keys.starts_with(b"uptime") {
+                        println!("up {}", snakian_kernel::time::Instant::now());
+                    } else if keys.starts_with(b"mce") {
+                        // Corrected errors don't raise an exception, they only show up when polled.
+                        snakian_kernel::mce::poll();
+                        println!("{}", snakian_kernel::mce::stats());
+                    } else if keys.starts_with(b"watchdog") {
+                        println!("{}", snakian_kernel::watchdog::stats());
+                    } else if keys.starts_with(b"interrupts") {
+                        let table = snakian_kernel::irq::InterruptTable;
+                        println!("{}", table);
+                        serial_println!("{}", table);
+                    } else if keys.starts_with(b"stacks") {
+                        memory::stack::for_each_stack_usage(|usage| {
+                            println!(
+                                "{}: {}/{} bytes ({}%)",
+                                usage.name, usage.high_water_mark, usage.size, usage.percent()
+                            );
+                        });
                     }
                     keys.iter_mut().for_each(|x| *x = 0);
                     i = 0;
-                    print!("\n")
                 } else {
                     print!("{}", key.unwrap());
                     keys[i] = key.unwrap() as u8;
@@ -111,8 +148,14 @@ fn os_entry_point(boot_info: &'static mut BootInfo) -> ! {
             key = None;
         }
         drop(lock);
-        // This hlt is necessary because the keyboard driver needs to be able to unlock the keyboard
-        instructions::hlt(); // or asm!("hlt", options(nomem, nostack));
+        // Sleep until the next interrupt, which might queue work. The queue is checked with interrupts disabled,
+        // and `sti; hlt` only takes an interrupt once halted, so nothing queued after the check goes unnoticed.
+        instructions::interrupts::disable();
+        if deferred::is_empty() {
+            instructions::interrupts::enable_and_hlt();
+        } else {
+            instructions::interrupts::enable();
+        }
     }
 }
 
@@ -0,0 +1,344 @@
+//! Machine check architecture.
+//!
+//! [`init`] reports errors the banks latched before the kernel started, enables error reporting in every bank and
+//! sets CR4.MCE, so hardware errors raise a machine check exception instead of shutting the CPU down. The exception
+//! runs on its own IST stack and calls [`handle_machine_check`]. Corrected errors are logged over serial and cleared,
+//! uncorrected ones end up on the panic screen.
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::registers::{
+    control::{Cr4, Cr4Flags},
+    model_specific::Msr,
+};
+
+use crate::{prelude::*, serial_println};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+/// The first of four registers per bank: CTL, STATUS, ADDR and MISC.
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xff;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+/// The instruction pointer on the stack can be used to restart the interrupted code.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// The instruction pointer on the stack points at the instruction that caused the error.
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_EN: u64 = 1 << 60;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+
+/// The maximum amount of banks looked at.
+pub const MAX_BANKS: usize = 32;
+
+static BANKS: AtomicU64 = AtomicU64::new(0);
+static CORRECTED: AtomicU64 = AtomicU64::new(0);
+static UNCORRECTED: AtomicU64 = AtomicU64::new(0);
+
+fn bank_msr(bank: usize, offset: u32) -> Msr {
+    Msr::new(IA32_MC0_CTL + bank as u32 * 4 + offset)
+}
+
+/// Enables machine checks, after reporting errors that were latched before. Has to be called after the IDT and the
+/// IST stacks are set up.
+pub fn init() {
+    // SAFETY: cpuid is always available in long mode.
+    let features = unsafe { __cpuid(1) }.edx;
+    if features & (1 << 7) == 0 {
+        warn!("CPU doesn't support machine check exceptions");
+        return;
+    }
+    if features & (1 << 14) != 0 {
+        // SAFETY: the CPU supports the machine check architecture, so its MSRs exist.
+        let capabilities = unsafe { Msr::new(IA32_MCG_CAP).read() };
+        let banks = ((capabilities & MCG_CAP_COUNT) as usize).min(MAX_BANKS);
+        BANKS.store(banks as u64, Ordering::Relaxed);
+        let latched = poll();
+        if latched != 0 {
+            warn!("{} machine check errors were latched before boot", latched);
+        }
+        // SAFETY: enabling error reporting only makes the banks log and signal errors.
+        unsafe {
+            if capabilities & MCG_CAP_CTL_P != 0 {
+                Msr::new(IA32_MCG_CTL).write(u64::MAX);
+            }
+            for bank in 0..banks {
+                bank_msr(bank, 0).write(u64::MAX);
+                bank_msr(bank, 1).write(0);
+            }
+        }
+        info!("Machine check architecture enabled with {} banks", banks);
+    } else {
+        info!("Machine check exceptions enabled, the CPU has no error banks");
+    }
+    // SAFETY: the IDT has a handler for machine checks.
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+}
+
+/// One error logged by a bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankError {
+    pub bank: usize,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    /// Reads the error a bank logged, if there is one.
+    fn read(bank: usize) -> Option<BankError> {
+        // SAFETY: the bank exists, reading its registers has no side effects.
+        unsafe {
+            let status = bank_msr(bank, 1).read();
+            if status & STATUS_VAL == 0 {
+                return None;
+            }
+            Some(BankError {
+                bank,
+                status,
+                addr: (status & STATUS_ADDRV != 0).then(|| bank_msr(bank, 2).read()),
+                misc: (status & STATUS_MISCV != 0).then(|| bank_msr(bank, 3).read()),
+            })
+        }
+    }
+
+    /// Clears the bank, so it can log the next error.
+    fn clear(&self) {
+        // SAFETY: writing zero to a status register only clears it.
+        unsafe { bank_msr(self.bank, 1).write(0) };
+    }
+
+    pub fn is_uncorrected(&self) -> bool {
+        self.status & STATUS_UC != 0
+    }
+
+    /// The state of the processor is corrupted, whatever was running can't continue.
+    pub fn is_context_corrupt(&self) -> bool {
+        self.status & STATUS_PCC != 0
+    }
+
+    /// The architectural error code.
+    pub fn mca_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    pub fn model_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    /// How many corrected errors the bank counted, if it counts them.
+    pub fn corrected_count(&self) -> u16 {
+        ((self.status >> 38) & 0x7fff) as u16
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bank {}: {} {} (status {:#018x}, model code {:#06x})",
+            self.bank,
+            if self.is_uncorrected() { "uncorrected" } else { "corrected" },
+            McaCode(self.mca_code()),
+            self.status,
+            self.model_code()
+        )?;
+        let flags = [
+            (STATUS_OVER, "overflow"),
+            (STATUS_EN, "signaled"),
+            (STATUS_PCC, "context corrupt"),
+        ];
+        for (_, name) in flags.iter().filter(|(bit, _)| self.status & bit != 0) {
+            write!(f, ", {}", name)?;
+        }
+        if !self.is_uncorrected() && self.corrected_count() != 0 {
+            write!(f, ", {} corrected", self.corrected_count())?;
+        }
+        if let Some(addr) = self.addr {
+            write!(f, ", addr {:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {:#x}", misc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the architectural MCA error code, see the Intel SDM volume 3, "Interpreting the MCA Error Codes".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McaCode(pub u16);
+
+impl fmt::Display for McaCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const LEVELS: [&str; 4] = ["L0", "L1", "L2", "generic level"];
+        const TRANSACTIONS: [&str; 4] = ["instruction", "data", "generic", "unknown transaction"];
+        const REQUESTS: [&str; 9] = [
+            "generic", "read", "write", "data read", "data write", "instruction fetch", "prefetch", "eviction", "snoop",
+        ];
+        const CHANNEL_OPS: [&str; 5] = ["generic", "read", "write", "address/command", "scrubbing"];
+        let code = self.0 & 0xefff;
+        let level = LEVELS[(code & 0b11) as usize];
+        let request = |bits: u16| REQUESTS.get(bits as usize).copied().unwrap_or("reserved");
+        match code {
+            0x0000 => write!(f, "no error"),
+            0x0001 => write!(f, "unclassified error"),
+            0x0002 => write!(f, "microcode ROM parity error"),
+            0x0003 => write!(f, "external error"),
+            0x0004 => write!(f, "FRC error"),
+            0x0005 => write!(f, "internal parity error"),
+            0x0006 => write!(f, "SMM handler code access violation"),
+            0x0400 => write!(f, "internal timer error"),
+            0x0e0b => write!(f, "I/O error"),
+            0x0401..=0x07ff => write!(f, "internal unclassified error"),
+            _ if code & 0xfff0 == 0x0000 && code & 0b1100 == 0b1100 => {
+                write!(f, "{} generic cache hierarchy error", level)
+            }
+            _ if code & 0xfff0 == 0x0010 => write!(
+                f,
+                "{} {} TLB error",
+                level,
+                TRANSACTIONS[((code >> 2) & 0b11) as usize]
+            ),
+            _ if code & 0xff80 == 0x0080 => write!(
+                f,
+                "memory controller {} error on channel {}",
+                CHANNEL_OPS.get(((code >> 4) & 0b111) as usize).copied().unwrap_or("reserved"),
+                code & 0xf
+            ),
+            _ if code & 0xff00 == 0x0100 => write!(
+                f,
+                "{} {} cache {} error",
+                level,
+                TRANSACTIONS[((code >> 2) & 0b11) as usize],
+                request((code >> 4) & 0xf)
+            ),
+            _ if code & 0xf800 == 0x0800 => write!(
+                f,
+                "{} bus {} error{}",
+                level,
+                request((code >> 4) & 0xf),
+                if code & (1 << 8) != 0 { " (timeout)" } else { "" }
+            ),
+            _ => write!(f, "unknown error {:#06x}", self.0),
+        }
+    }
+}
+
+/// Calls `f` with every error the banks logged, and clears them.
+fn for_each_error(mut f: impl FnMut(&BankError)) -> usize {
+    let mut found = 0;
+    for bank in 0..BANKS.load(Ordering::Relaxed) as usize {
+        if let Some(error) = BankError::read(bank) {
+            let counter = if error.is_uncorrected() { &UNCORRECTED } else { &CORRECTED };
+            counter.fetch_add(1, Ordering::Relaxed);
+            f(&error);
+            error.clear();
+            found += 1;
+        }
+    }
+    found
+}
+
+/// Logs and clears the errors the banks logged without raising a machine check. Returns how many there were.
+pub fn poll() -> usize {
+    for_each_error(|error| warn!("Machine check {}", error))
+}
+
+/// The errors found by a machine check exception.
+pub struct MachineCheck {
+    pub mcg_status: u64,
+    errors: [Option<BankError>; MAX_BANKS],
+    len: usize,
+}
+
+impl MachineCheck {
+    pub fn errors(&self) -> impl Iterator<Item = &BankError> {
+        self.errors[..self.len].iter().flatten()
+    }
+
+    /// Whether the interrupted code can continue.
+    pub fn is_recoverable(&self) -> bool {
+        self.mcg_status & MCG_STATUS_RIPV != 0 && !self.errors().any(BankError::is_uncorrected)
+    }
+}
+
+impl fmt::Display for MachineCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "machine check (MCG_STATUS {:#x}{}{})",
+            self.mcg_status,
+            if self.mcg_status & MCG_STATUS_RIPV != 0 { ", restartable" } else { "" },
+            if self.mcg_status & MCG_STATUS_EIPV != 0 { ", rip points at the error" } else { "" },
+        )?;
+        if self.len == 0 {
+            write!(f, "\nno bank logged an error")?;
+        }
+        for error in self.errors() {
+            write!(f, "\n{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Called from the machine check exception. Collects and clears the logged errors, and logs the corrected ones over
+/// serial. The caller has to stop the kernel if the result isn't [recoverable](MachineCheck::is_recoverable).
+pub fn handle_machine_check() -> MachineCheck {
+    // SAFETY: machine check exceptions are only enabled if the CPU has the machine check MSRs.
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    let mut check = MachineCheck {
+        mcg_status,
+        errors: [None; MAX_BANKS],
+        len: 0,
+    };
+    for_each_error(|error| {
+        // Only serial output here, the logger takes locks the interrupted code might hold.
+        if !error.is_uncorrected() {
+            serial_println!("Machine check {}", error);
+        }
+        check.errors[check.len] = Some(*error);
+        check.len += 1;
+    });
+    // SAFETY: clearing MCIP allows the next machine check, the current one is handled. Another one while it is set
+    // would shut the CPU down.
+    unsafe { Msr::new(IA32_MCG_STATUS).write(0) };
+    check
+}
+
+/// Counters of the machine check banks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MceStats {
+    pub banks: usize,
+    pub corrected: u64,
+    pub uncorrected: u64,
+}
+
+pub fn stats() -> MceStats {
+    MceStats {
+        banks: BANKS.load(Ordering::Relaxed) as usize,
+        corrected: CORRECTED.load(Ordering::Relaxed),
+        uncorrected: UNCORRECTED.load(Ordering::Relaxed),
+    }
+}
+
+impl fmt::Display for MceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "machine checks: {} banks, {} corrected errors, {} uncorrected errors",
+            self.banks, self.corrected, self.uncorrected
+        )
+    }
+}
@@ -0,0 +1,92 @@
+//! A bump allocator.
+//!
+//! Allocations just move a pointer forward, which makes them very fast. Memory is only reclaimed
+//! once every allocation has been freed, so this is mostly useful as a baseline to compare the other allocators against.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+use super::{align_up, AllocatorStats, Counters, HeapStats, Locked};
+
+/// A heap allocator that hands out memory linearly.
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    /// The start of the unused part of the heap.
+    next: usize,
+    counters: Counters,
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BumpAllocator {
+    /// Creates an empty allocator. [`BumpAllocator::init`] must be called before it can be used.
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            counters: Counters::new(),
+        }
+    }
+
+    /// Initializes the allocator with the given heap bounds.
+    /// # Safety
+    /// The given memory range must be mapped, unused, and this function must only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    /// Allocates a block of memory for the given layout. Returns a null pointer if the heap is used up.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+        if alloc_end > self.heap_end {
+            return ptr::null_mut();
+        }
+        self.next = alloc_end;
+        self.counters.record_alloc(layout.size());
+        alloc_start as *mut u8
+    }
+
+    /// Frees a block of memory. The memory only becomes reusable once every allocation has been freed.
+    /// # Safety
+    /// The pointer must have been returned by `allocate` with the same layout, and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, _ptr: *mut u8, layout: Layout) {
+        self.counters.record_dealloc(layout.size());
+        if self.counters.live_allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+}
+
+super::impl_raw_allocator!(BumpAllocator);
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().deallocate(ptr, layout) }
+    }
+}
+
+impl AllocatorStats for BumpAllocator {
+    fn stats(&self) -> HeapStats {
+        // Only the memory behind `next` can be handed out, so it is always a single block.
+        let free = self.heap_end - self.next;
+        self.counters.to_stats(free, free)
+    }
+}
@@ -0,0 +1,351 @@
+//! A debugging wrapper around a heap allocator.
+//!
+//! Every allocation is surrounded by red zones and gets a header that records its layout and where it was made:
+//!
+//! ```text
+//! | padding | BlockHeader | red zone | user data | red zone |
+//! ```
+//!
+//! New allocations are filled with [`UNINIT_BYTE`] and freed memory with [`POISON_BYTE`], so reading uninitialized
+//! or freed memory shows up as an obvious pattern instead of plausible data. The red zones are checked when the block
+//! is freed, and a corrupted one panics with the location of the allocation it belongs to.
+//!
+//! All live blocks are kept in a list, so outstanding allocations can be listed at any point (see [`report`]).
+//!
+//! Where an allocation was made is found by following the frame pointers up from the allocator. `Box`, `Vec` and
+//! friends reach the allocator through `alloc`, so the first few return addresses are inside of it. The recorded
+//! [`AllocationSite`] keeps a few of them, and shows the first one outside of the allocator once symbols are loaded.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt, mem,
+    ptr::{self, NonNull},
+};
+
+use super::{align_up, AllocatorStats, HeapStats, Locked, RawAllocator};
+use crate::{
+    backtrace::{self, Backtrace},
+    serial_println,
+};
+
+/// The size of the red zone on each side of an allocation.
+pub const RED_ZONE: usize = 16;
+/// The byte red zones are filled with.
+pub const RED_ZONE_BYTE: u8 = 0xfd;
+/// The byte fresh allocations are filled with.
+pub const UNINIT_BYTE: u8 = 0xcd;
+/// The byte freed memory is filled with.
+pub const POISON_BYTE: u8 = 0xdd;
+/// Marks a valid block header. Cleared on free, so double frees are caught.
+const HEADER_MAGIC: u64 = 0x4845_4150_4845_4144; // "DAEHPAEH"
+/// The amount of return addresses kept per allocation.
+const SITE_FRAMES: usize = 8;
+/// Functions that are part of getting to the allocator, rather than the code that wanted the memory.
+const ALLOCATOR_PATHS: [&str; 9] = [
+    "__rust_",
+    "__rg_",
+    "__rdl_",
+    "alloc::",
+    "<alloc::",
+    "core::",
+    "<core::",
+    "snakian_kernel::memory::allocator::",
+    "<snakian_kernel::memory::allocator::",
+];
+
+/// The return addresses leading to an allocation, innermost first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationSite {
+    frames: [u64; SITE_FRAMES],
+    len: usize,
+}
+
+impl AllocationSite {
+    /// Records the frames of the code calling this function.
+    #[inline(never)]
+    pub fn capture() -> AllocationSite {
+        let backtrace = Backtrace::capture();
+        let frames = backtrace.frames();
+        let mut site = AllocationSite {
+            frames: [0; SITE_FRAMES],
+            len: frames.len().min(SITE_FRAMES),
+        };
+        site.frames[..site.len].copy_from_slice(&frames[..site.len]);
+        site
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    /// The return address into the code that made the allocation, if symbols are available to find it.
+    pub fn caller(&self) -> Option<(u64, &'static str, u64)> {
+        self.frames().iter().find_map(|addr| {
+            // Return addresses point after the call, which might already be the next function.
+            let (name, offset) = backtrace::symbolize(addr - 1)?;
+            let internal = ALLOCATOR_PATHS.iter().any(|path| name.starts_with(path));
+            (!internal).then_some((*addr, name, offset + 1))
+        })
+    }
+}
+
+impl fmt::Display for AllocationSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((_, name, offset)) = self.caller() {
+            return write!(f, "{}+{:#x}", name, offset);
+        }
+        // Without symbols, show the raw chain.
+        if self.len == 0 {
+            return write!(f, "<unknown>");
+        }
+        for (i, addr) in self.frames().iter().enumerate() {
+            write!(f, "{}{:#x}", if i == 0 { "" } else { " <- " }, addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// The bookkeeping in front of every allocation.
+#[repr(C)]
+struct BlockHeader {
+    magic: u64,
+    /// The layout the caller asked for.
+    layout: Layout,
+    /// The distance from the start of the underlying block to the user data.
+    offset: usize,
+    /// A running number, used to find allocations made after a certain point.
+    serial: u64,
+    site: AllocationSite,
+    prev: Option<NonNull<BlockHeader>>,
+    next: Option<NonNull<BlockHeader>>,
+}
+
+/// Information about a live allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationInfo {
+    pub ptr: *const u8,
+    pub size: usize,
+    pub serial: u64,
+    /// Where the allocation was made.
+    pub site: AllocationSite,
+}
+
+/// Wraps an allocator with poisoning, red zones and allocation tracking.
+pub struct DebugAllocator<A> {
+    inner: A,
+    /// The most recent live allocation. Older ones are reached through `prev`.
+    last: Option<NonNull<BlockHeader>>,
+    next_serial: u64,
+}
+
+// SAFETY: the block list is only reached through the allocator, which is behind a lock.
+unsafe impl<A: Send> Send for DebugAllocator<A> {}
+
+impl<A> DebugAllocator<A> {
+    /// Wraps the given allocator.
+    pub const fn new(inner: A) -> Self {
+        DebugAllocator {
+            inner,
+            last: None,
+            next_serial: 0,
+        }
+    }
+
+    /// The serial number the next allocation will get. Pass this to [`DebugAllocator::for_each_since`] later on to
+    /// see what was allocated in between.
+    pub fn mark(&self) -> u64 {
+        self.next_serial
+    }
+
+    /// Calls `f` for every live allocation made at or after `mark`, newest first. `f` runs with the allocator locked,
+    /// so it must not allocate or print, see [`DebugAllocator::collect_since`].
+    pub fn for_each_since(&self, mark: u64, mut f: impl FnMut(AllocationInfo)) {
+        let mut current = self.last;
+        while let Some(header) = current {
+            // SAFETY: every header in the list belongs to a live allocation.
+            let header = unsafe { header.as_ref() };
+            // Serials only go up, so everything further down the list is older.
+            if header.serial < mark {
+                break;
+            }
+            f(AllocationInfo {
+                ptr: user_ptr(header),
+                size: header.layout.size(),
+                serial: header.serial,
+                site: header.site,
+            });
+            current = header.prev;
+        }
+    }
+
+    /// Copies the live allocations made at or after `mark` into `out`, newest first, and returns how many there are.
+    /// That can be more than fit into `out`.
+    pub fn collect_since(&self, mark: u64, out: &mut [Option<AllocationInfo>]) -> usize {
+        let mut count = 0;
+        self.for_each_since(mark, |info| {
+            if let Some(slot) = out.get_mut(count) {
+                *slot = Some(info);
+            }
+            count += 1;
+        });
+        count
+    }
+
+    /// The layout of the underlying block, and the offset of the user data in it.
+    fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+        let align = layout.align().max(mem::align_of::<BlockHeader>());
+        let offset = align_up(mem::size_of::<BlockHeader>() + RED_ZONE, align);
+        let size = offset.checked_add(layout.size())?.checked_add(RED_ZONE)?;
+        Some((Layout::from_size_align(size, align).ok()?, offset))
+    }
+}
+
+impl<A: RawAllocator> DebugAllocator<A> {
+    fn allocate(&mut self, layout: Layout, site: AllocationSite) -> *mut u8 {
+        let Some((outer, offset)) = Self::outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let block = self.inner.allocate(outer);
+        if block.is_null() {
+            return block;
+        }
+        // SAFETY: the block is large enough for the header, both red zones and the user data.
+        unsafe {
+            let user = block.add(offset);
+            ptr::write_bytes(user.sub(RED_ZONE), RED_ZONE_BYTE, RED_ZONE);
+            ptr::write_bytes(user, UNINIT_BYTE, layout.size());
+            ptr::write_bytes(user.add(layout.size()), RED_ZONE_BYTE, RED_ZONE);
+
+            let header = user.sub(RED_ZONE + mem::size_of::<BlockHeader>()) as *mut BlockHeader;
+            header.write(BlockHeader {
+                magic: HEADER_MAGIC,
+                layout,
+                offset,
+                serial: self.next_serial,
+                site,
+                prev: self.last,
+                next: None,
+            });
+            let header = NonNull::new_unchecked(header);
+            if let Some(mut last) = self.last {
+                last.as_mut().next = Some(header);
+            }
+            self.last = Some(header);
+            self.next_serial += 1;
+            user
+        }
+    }
+
+    /// # Safety
+    /// See [`RawAllocator::deallocate`].
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let header_ptr = unsafe { ptr.sub(RED_ZONE + mem::size_of::<BlockHeader>()) } as *mut BlockHeader;
+        let header = unsafe { &mut *header_ptr };
+        if header.magic != HEADER_MAGIC {
+            panic!("Heap: free of {:p} which is not a live allocation (double free?)", ptr);
+        }
+        if header.layout != layout {
+            panic!(
+                "Heap: {:p} allocated at {} with {:?} but freed with {:?}",
+                ptr, header.site, header.layout, layout
+            );
+        }
+        // SAFETY: both red zones are part of the block.
+        unsafe {
+            if let Some(i) = (0..RED_ZONE).find(|i| *ptr.sub(RED_ZONE - i) != RED_ZONE_BYTE) {
+                panic!(
+                    "Heap: red zone in front of {:p} ({} bytes, allocated at {}) overwritten {} bytes before the start",
+                    ptr,
+                    layout.size(),
+                    header.site,
+                    RED_ZONE - i
+                );
+            }
+            if let Some(i) = (0..RED_ZONE).find(|i| *ptr.add(layout.size() + i) != RED_ZONE_BYTE) {
+                panic!(
+                    "Heap: red zone behind {:p} ({} bytes, allocated at {}) overwritten {} bytes past the end",
+                    ptr,
+                    layout.size(),
+                    header.site,
+                    i
+                );
+            }
+        }
+
+        // Unlink the block.
+        unsafe {
+            if let Some(mut prev) = header.prev {
+                prev.as_mut().next = header.next;
+            }
+            match header.next {
+                Some(mut next) => next.as_mut().prev = header.prev,
+                None => self.last = header.prev,
+            }
+        }
+
+        let (outer, offset) = Self::outer_layout(layout).unwrap();
+        header.magic = 0;
+        unsafe {
+            let block = ptr.sub(offset);
+            // Poison everything but the header. Its magic is cleared, so a second free of the same pointer is caught
+            // as long as the memory hasn't been handed out again.
+            ptr::write_bytes(block, POISON_BYTE, header_ptr as usize - block as usize);
+            ptr::write_bytes(ptr.sub(RED_ZONE), POISON_BYTE, layout.size() + 2 * RED_ZONE);
+            self.inner.deallocate(block, outer);
+        }
+    }
+}
+
+impl<A: RawAllocator> RawAllocator for DebugAllocator<A> {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.inner.init(heap_start, heap_size) }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        DebugAllocator::allocate(self, layout, AllocationSite::capture())
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { DebugAllocator::deallocate(self, ptr, layout) }
+    }
+}
+
+unsafe impl<A: RawAllocator + Send> GlobalAlloc for Locked<DebugAllocator<A>> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Walking the stack takes the stack table lock, so it happens before the allocator is locked.
+        let site = AllocationSite::capture();
+        self.lock().allocate(layout, site)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().deallocate(ptr, layout) }
+    }
+}
+
+/// The stats of the wrapped allocator. Sizes include the headers and red zones.
+impl<A: AllocatorStats> AllocatorStats for DebugAllocator<A> {
+    fn stats(&self) -> HeapStats {
+        self.inner.stats()
+    }
+}
+
+fn user_ptr(header: &BlockHeader) -> *const u8 {
+    (header as *const BlockHeader as usize + mem::size_of::<BlockHeader>() + RED_ZONE) as *const u8
+}
+
+/// Prints allocations gathered with [`DebugAllocator::collect_since`] to the serial port. `total` is the amount
+/// `collect_since` returned, allocations that didn't fit are only counted. Call it with the allocator unlocked.
+pub fn report(allocations: &[Option<AllocationInfo>], total: usize) {
+    for info in allocations.iter().flatten() {
+        serial_println!(
+            "  leaked {} bytes at {:p} (allocation #{}) from {}",
+            info.size,
+            info.ptr,
+            info.serial,
+            info.site
+        );
+    }
+    if total > allocations.len() {
+        serial_println!("  and {} more", total - allocations.len());
+    }
+}
@@ -0,0 +1,140 @@
+//! A fixed size block (slab style) allocator.
+//!
+//! Small allocations are rounded up to one of the [`BLOCK_SIZES`] and served from a free list for that size.
+//! Freed blocks go back to their list instead of the heap, so allocating the same size again is very cheap.
+//! Allocations larger than the biggest block size go to a [`LinkedListAllocator`].
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+};
+
+use super::{linked_list::LinkedListAllocator, AllocatorStats, Counters, HeapStats, Locked};
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as the block alignment.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// A free block. Lives inside the free block itself.
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Const functions can't build values holding `&mut` types, constants can.
+const EMPTY_LIST: Option<&'static mut ListNode> = None;
+const EMPTY_LISTS: [Option<&'static mut ListNode>; BLOCK_SIZES.len()] = [EMPTY_LIST; BLOCK_SIZES.len()];
+
+/// A heap allocator that keeps a free list per block size.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    /// The amount of blocks in each free list.
+    list_lengths: [usize; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+    counters: Counters,
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty allocator. [`FixedSizeBlockAllocator::init`] must be called before it can be used.
+    pub const fn new() -> Self {
+        FixedSizeBlockAllocator {
+            list_heads: EMPTY_LISTS,
+            list_lengths: [0; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+            counters: Counters::new(),
+        }
+    }
+
+    /// Initializes the allocator with the given heap bounds.
+    /// # Safety
+    /// The given memory range must be mapped, unused, and this function must only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.fallback_allocator.init(heap_start, heap_size) };
+    }
+
+    /// Allocates a block of memory for the given layout. Returns a null pointer if there is no memory left.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    self.list_lengths[index] -= 1;
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // No block left in the list, so carve a new one out of the fallback allocator.
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                    self.fallback_allocator.allocate(layout)
+                }
+            },
+            None => self.fallback_allocator.allocate(layout),
+        };
+        if !ptr.is_null() {
+            self.counters.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    /// Frees a block of memory that was allocated with [`FixedSizeBlockAllocator::allocate`].
+    /// # Safety
+    /// The pointer must have been returned by `allocate` with the same layout, and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // Make sure the block can hold a ListNode.
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                unsafe {
+                    new_node_ptr.write(new_node);
+                    self.list_heads[index] = Some(&mut *new_node_ptr);
+                }
+                self.list_lengths[index] += 1;
+            }
+            None => unsafe { self.fallback_allocator.deallocate(ptr, layout) },
+        }
+        self.counters.record_dealloc(layout.size());
+    }
+}
+
+/// Chooses the block size for the given layout. Returns `None` if the layout is too big for any block.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+super::impl_raw_allocator!(FixedSizeBlockAllocator);
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().deallocate(ptr, layout) }
+    }
+}
+
+impl AllocatorStats for FixedSizeBlockAllocator {
+    fn stats(&self) -> HeapStats {
+        let (mut free, mut largest) = self.fallback_allocator.free_space();
+        // Blocks sitting in the free lists can be handed out as well.
+        for (size, length) in BLOCK_SIZES.iter().zip(self.list_lengths.iter()) {
+            free += size * length;
+            if *length > 0 {
+                largest = largest.max(*size);
+            }
+        }
+        self.counters.to_stats(free, largest)
+    }
+}
@@ -0,0 +1,220 @@
+//! A first-fit free list allocator.
+//!
+//! Free regions are kept in a singly linked list sorted by address, with the list nodes stored inside the free memory itself.
+//! Adjacent free regions are merged on deallocation so the heap doesn't fragment into tiny pieces over time.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr,
+};
+
+use super::{align_up, AllocatorStats, Counters, HeapStats, Locked};
+
+/// A node in the free list. Lives at the start of the free region it describes.
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+/// The dummy head of an empty list. Const functions can't build values holding `&mut` types, constants can.
+const EMPTY_HEAD: ListNode = ListNode { size: 0, next: None };
+
+impl ListNode {
+    fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// A heap allocator that keeps track of free memory with a linked list.
+pub struct LinkedListAllocator {
+    /// A dummy node of size 0 that points to the first free region.
+    head: ListNode,
+    counters: Counters,
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkedListAllocator {
+    /// Creates an empty allocator. [`LinkedListAllocator::init`] must be called before it can be used.
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: EMPTY_HEAD,
+            counters: Counters::new(),
+        }
+    }
+
+    /// Initializes the allocator with the given heap bounds.
+    /// # Safety
+    /// The given memory range must be mapped, unused, and this function must only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.add_free_region(heap_start, heap_size) };
+    }
+
+    /// Adds the given region to the free list, merging it with its neighbours if they are adjacent.
+    /// # Safety
+    /// The region must be unused and at least large enough to hold a `ListNode`.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // Find the node after which the region has to be inserted to keep the list sorted.
+        let mut current = &mut self.head;
+        while let Some(ref next) = current.next {
+            if next.start_addr() > addr {
+                break;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+
+        let mut size = size;
+        // Merge with the following region if it starts right where we end.
+        if let Some(next) = current.next.take() {
+            if addr + size == next.start_addr() {
+                size += next.size;
+                current.next = next.next.take();
+            } else {
+                current.next = Some(next);
+            }
+        }
+        // Merge into the previous region if it ends right where we start. The head is a dummy node, so never merge into it.
+        if current.size != 0 && current.end_addr() == addr {
+            current.size += size;
+            return;
+        }
+
+        let mut node = ListNode::new(size);
+        node.next = current.next.take();
+        let node_ptr = addr as *mut ListNode;
+        // SAFETY: the caller guarantees the region is unused and large enough.
+        unsafe {
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
+    }
+
+    /// Looks for a free region that fits the given size and alignment, and removes it from the list.
+    /// Returns the region and the start address of the allocation inside it.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let found = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return found;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+        None
+    }
+
+    /// Tries to use the given region for an allocation with the given size and alignment.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let front_padding = alloc_start - region.start_addr();
+        if front_padding > 0 && front_padding < mem::size_of::<ListNode>() {
+            // The padding in front has to be able to hold a ListNode as well, otherwise it would be lost.
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // The rest of the region is too small to hold a ListNode, so it would be lost.
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    /// Allocates a block of memory for the given layout. Returns a null pointer if there is no region large enough.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let alloc_end = alloc_start + size;
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            // Give back whatever is left on either side of the allocation.
+            let front_padding = alloc_start - region_start;
+            if front_padding >= mem::size_of::<ListNode>() {
+                unsafe { self.add_free_region(region_start, front_padding) };
+            }
+            let excess_size = region_end - alloc_end;
+            if excess_size > 0 {
+                unsafe { self.add_free_region(alloc_end, excess_size) };
+            }
+            self.counters.record_alloc(layout.size());
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Frees a block of memory that was allocated with [`LinkedListAllocator::allocate`].
+    /// # Safety
+    /// The pointer must have been returned by `allocate` with the same layout, and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        unsafe { self.add_free_region(ptr as usize, size) };
+        self.counters.record_dealloc(layout.size());
+    }
+
+    /// Returns the total size of all free regions and the size of the largest one.
+    pub fn free_space(&self) -> (usize, usize) {
+        let mut total = 0;
+        let mut largest = 0;
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            total += region.size;
+            largest = largest.max(region.size);
+            current = region;
+        }
+        (total, largest)
+    }
+
+    /// Adjusts the layout so the allocated region is able to hold a `ListNode` when it's freed.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+super::impl_raw_allocator!(LinkedListAllocator);
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().deallocate(ptr, layout) }
+    }
+}
+
+impl AllocatorStats for LinkedListAllocator {
+    fn stats(&self) -> HeapStats {
+        let (free, largest) = self.free_space();
+        self.counters.to_stats(free, largest)
+    }
+}
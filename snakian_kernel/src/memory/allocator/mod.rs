@@ -0,0 +1,181 @@
+//! Heap allocator implementations for the kernel heap.
+//!
+//! The allocator used for the kernel heap is picked at build time through cargo features, which the top level crate
+//! forwards. Enabling bump or fixed-block replaces the default:
+//! - `allocator-bump`: a bump allocator. Very fast, but only frees memory once every allocation is gone.
+//! - `allocator-linked-list` (default): a first-fit free list that merges adjacent free regions.
+//! - `allocator-fixed-block`: power of two sized block lists, falling back to the free list for large allocations.
+//!
+//! Every allocator reports the same [`HeapStats`], so they can be compared against each other.
+//!
+//! With the `heap-debug` feature, the chosen allocator is wrapped in a [`debug::DebugAllocator`] that poisons freed
+//! memory, checks red zones around every allocation, and keeps track of live allocations to find leaks.
+
+use core::{alloc::Layout, fmt};
+
+use crate::sync::{IrqMutex, IrqMutexGuard};
+
+pub mod bump;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod fixed_size_block;
+pub mod linked_list;
+
+/// A wrapper around [`IrqMutex`] so that `GlobalAlloc` can be implemented for allocators defined in this crate.
+/// Interrupt handlers may allocate, so the allocator keeps interrupts disabled while it is locked.
+pub struct Locked<A> {
+    inner: IrqMutex<A>,
+}
+
+impl<A> Locked<A> {
+    /// Wraps the given allocator.
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: IrqMutex::new(inner),
+        }
+    }
+
+    /// Locks the allocator.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// Aligns the given address upwards to `align`. `align` must be a power of two.
+pub fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// A snapshot of the state of a heap allocator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes requested by allocations that haven't been freed yet.
+    pub bytes_in_use: usize,
+    /// The highest `bytes_in_use` has ever been.
+    pub peak_bytes_in_use: usize,
+    /// The amount of allocations that haven't been freed yet.
+    pub live_allocations: usize,
+    /// The amount of allocations made since the heap was initialized.
+    pub total_allocations: usize,
+    /// Bytes that the allocator could still hand out.
+    pub free_bytes: usize,
+    /// The largest single allocation that could currently succeed.
+    pub largest_free_block: usize,
+}
+
+impl HeapStats {
+    /// How fragmented the free memory is, in percent.
+    /// 0 means all free memory is in one block, values close to 100 mean it is split into lots of small pieces.
+    pub fn fragmentation(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        100 - self.largest_free_block * 100 / self.free_bytes
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "in use: {} bytes (peak {} bytes)", self.bytes_in_use, self.peak_bytes_in_use)?;
+        writeln!(
+            f,
+            "allocations: {} live, {} total",
+            self.live_allocations, self.total_allocations
+        )?;
+        write!(
+            f,
+            "free: {} bytes, largest block {} bytes, {}% fragmented",
+            self.free_bytes,
+            self.largest_free_block,
+            self.fragmentation()
+        )
+    }
+}
+
+/// Implemented by every heap allocator so they can be compared.
+pub trait AllocatorStats {
+    /// Returns the current statistics of the allocator.
+    fn stats(&self) -> HeapStats;
+}
+
+/// The interface shared by every heap allocator strategy.
+pub trait RawAllocator {
+    /// Initializes the allocator with the given heap bounds.
+    /// # Safety
+    /// The given memory range must be mapped, unused, and this function must only be called once.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Allocates a block of memory for the given layout. Returns a null pointer if there is no memory left.
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// Frees a block of memory.
+    /// # Safety
+    /// The pointer must have been returned by `allocate` with the same layout, and must not be used afterwards.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+/// Implements [`RawAllocator`] by forwarding to the inherent methods of the same name.
+macro_rules! impl_raw_allocator {
+    ($allocator:ty) => {
+        impl super::RawAllocator for $allocator {
+            unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+                unsafe { <$allocator>::init(self, heap_start, heap_size) }
+            }
+
+            fn allocate(&mut self, layout: Layout) -> *mut u8 {
+                <$allocator>::allocate(self, layout)
+            }
+
+            unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+                unsafe { <$allocator>::deallocate(self, ptr, layout) }
+            }
+        }
+    };
+}
+pub(crate) use impl_raw_allocator;
+
+/// Usage counters shared by all allocator implementations.
+pub(crate) struct Counters {
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
+    live_allocations: usize,
+    total_allocations: usize,
+}
+
+impl Counters {
+    pub(crate) const fn new() -> Counters {
+        Counters {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            live_allocations: 0,
+            total_allocations: 0,
+        }
+    }
+
+    /// Records a successful allocation of `size` bytes.
+    pub(crate) fn record_alloc(&mut self, size: usize) {
+        self.bytes_in_use += size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+        self.live_allocations += 1;
+        self.total_allocations += 1;
+    }
+
+    /// Records that an allocation of `size` bytes was freed.
+    pub(crate) fn record_dealloc(&mut self, size: usize) {
+        self.bytes_in_use -= size;
+        self.live_allocations -= 1;
+    }
+
+    /// Builds a `HeapStats` from the counters and the given free memory information.
+    pub(crate) fn to_stats(&self, free_bytes: usize, largest_free_block: usize) -> HeapStats {
+        HeapStats {
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            live_allocations: self.live_allocations,
+            total_allocations: self.total_allocations,
+            free_bytes,
+            largest_free_block,
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Physically contiguous buffers for device DMA.
+//!
+//! Devices don't go through our page tables, so a buffer they read or write has to be contiguous in physical memory,
+//! and often has to sit below some address limit (16 MiB for ISA DMA, 4 GiB for 32 bit PCI devices), be aligned,
+//! or not cross a boundary. [`DmaBuffer`] takes care of all of that, maps the buffer into its own virtual region
+//! with the requested caching, and gives everything back when it is dropped.
+//!
+//! Write-combining needs an entry in the PAT. [`init`] reprograms PAT entry 1 (selected by `WRITE_THROUGH` alone)
+//! from write-through to write-combining, nothing else in the kernel maps memory as write-through only.
+
+use core::{
+    arch::asm,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr, slice,
+};
+
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::paging::{PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    frame_allocator::FRAME_SIZE,
+    paging,
+    region::{self, RegionError, RegionKind},
+    MapError, FRAME_ALLOCATOR,
+};
+use crate::{lock_once, prelude::*};
+
+/// The PAT model specific register.
+const IA32_PAT: u32 = 0x277;
+/// The PAT memory type for write-combining.
+const PAT_WRITE_COMBINING: u64 = 0x01;
+
+/// How the CPU caches a DMA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal write-back caching. Only for devices that snoop the CPU caches.
+    WriteBack,
+    /// No caching at all. Every access goes straight to memory.
+    Uncached,
+    /// Writes are collected and sent in bursts, reads aren't cached. Good for framebuffers and other write-only buffers.
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            // PAT entry 1, see the module docs.
+            CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Where a DMA buffer may be placed in physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// The buffer has to end at or below this physical address.
+    pub max_addr: u64,
+    /// The alignment of the physical start address. Anything below a page is rounded up to a page.
+    pub align: u64,
+    /// If set, the buffer must not cross a multiple of this (a power of two).
+    pub boundary: Option<u64>,
+}
+
+impl DmaConstraints {
+    /// Anywhere in physical memory.
+    pub const ANY: DmaConstraints = DmaConstraints {
+        max_addr: u64::MAX,
+        align: FRAME_SIZE,
+        boundary: None,
+    };
+    /// Below 4 GiB, for devices that can only do 32 bit addressing.
+    pub const BELOW_4G: DmaConstraints = DmaConstraints {
+        max_addr: 0x1_0000_0000,
+        align: FRAME_SIZE,
+        boundary: None,
+    };
+    /// Below 16 MiB without crossing a 64 KiB boundary, for the ISA DMA controller.
+    pub const ISA: DmaConstraints = DmaConstraints {
+        max_addr: 0x100_0000,
+        align: FRAME_SIZE,
+        boundary: Some(0x1_0000),
+    };
+}
+
+/// An error returned when allocating a DMA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The size is zero, or the alignment or boundary isn't a power of two.
+    InvalidRequest,
+    /// The buffer would be larger than the boundary it may not cross.
+    CrossesBoundary,
+    /// There is no contiguous physical range that satisfies the constraints.
+    OutOfMemory,
+    /// Reserving or mapping the virtual region failed.
+    Region(RegionError),
+}
+
+impl From<RegionError> for DmaError {
+    fn from(err: RegionError) -> Self {
+        DmaError::Region(err)
+    }
+}
+
+impl From<MapError> for DmaError {
+    fn from(err: MapError) -> Self {
+        DmaError::Region(RegionError::Map(err))
+    }
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaError::InvalidRequest => write!(f, "invalid DMA buffer request"),
+            DmaError::CrossesBoundary => write!(f, "buffer is larger than its boundary"),
+            DmaError::OutOfMemory => write!(f, "no contiguous physical memory matches the constraints"),
+            DmaError::Region(err) => write!(f, "failed to map the buffer: {:?}", err),
+        }
+    }
+}
+
+/// Types that can live in a DMA buffer: plain data for which all zero bytes are a valid value.
+/// # Safety
+/// Every bit pattern a device could write, including all zeros, must be a valid value of the type.
+pub unsafe trait DmaSafe: Copy {}
+
+unsafe impl DmaSafe for u8 {}
+unsafe impl DmaSafe for u16 {}
+unsafe impl DmaSafe for u32 {}
+unsafe impl DmaSafe for u64 {}
+unsafe impl<T: DmaSafe, const N: usize> DmaSafe for [T; N] {}
+
+/// A zeroed, physically contiguous buffer of `T`s, mapped into kernel memory.
+pub struct DmaBuffer<T: DmaSafe> {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+    /// The size of the buffer in bytes.
+    size: u64,
+    /// The amount of frames backing the buffer.
+    frames: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: DmaSafe> DmaBuffer<T> {
+    /// Allocates a buffer of `len` elements that satisfies `constraints`, mapped with the given cache mode.
+    pub fn new(len: usize, constraints: DmaConstraints, cache: CacheMode) -> Result<DmaBuffer<T>, DmaError> {
+        let size = len.checked_mul(mem::size_of::<T>()).ok_or(DmaError::InvalidRequest)? as u64;
+        if size == 0
+            || !constraints.align.is_power_of_two()
+            || constraints.boundary.map_or(false, |b| !b.is_power_of_two())
+        {
+            return Err(DmaError::InvalidRequest);
+        }
+        // The physical alignment has to be at least what T needs, so the buffer is aligned no matter where it's mapped.
+        let align = constraints.align.max(mem::align_of::<T>() as u64);
+        let frames = ((size + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        if constraints.boundary.map_or(false, |b| frames as u64 * FRAME_SIZE > b.max(FRAME_SIZE)) {
+            return Err(DmaError::CrossesBoundary);
+        }
+
+        let first = lock_once!(FRAME_ALLOCATOR)
+            .allocate_contiguous(frames, align, constraints.max_addr, constraints.boundary)
+            .ok_or(DmaError::OutOfMemory)?;
+        // SAFETY: the frames were just allocated, and nothing else knows about them yet.
+        unsafe { map(first, frames, cache) }
+            .map(|virt| {
+                debug!(
+                    "Allocated DMA buffer of {} bytes at {:#x} (phys {:#x}, {:?})",
+                    size,
+                    virt.as_u64(),
+                    first.start_address().as_u64(),
+                    cache
+                );
+                DmaBuffer {
+                    virt,
+                    phys: first.start_address(),
+                    len,
+                    size,
+                    frames,
+                    _marker: PhantomData,
+                }
+            })
+            .map_err(|err| {
+                unsafe { lock_once!(FRAME_ALLOCATOR).deallocate_contiguous(first, frames) };
+                err
+            })
+    }
+
+    /// The kernel virtual address of the buffer.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The physical address of the buffer, to be handed to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The physical address of the element at `index`.
+    pub fn phys_addr_of(&self, index: usize) -> PhysAddr {
+        assert!(index < self.len, "DMA buffer index {} out of bounds", index);
+        // Can't overflow, the whole buffer fits in `size`.
+        self.phys + (index * mem::size_of::<T>()) as u64
+    }
+
+    /// The size of the buffer in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<T: DmaSafe> Deref for DmaBuffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: the buffer is mapped, aligned and zero initialized, and T is valid for any bit pattern.
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+}
+
+impl<T: DmaSafe> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.len) }
+    }
+}
+
+impl<T: DmaSafe> fmt::Debug for DmaBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("virt", &self.virt)
+            .field("phys", &self.phys)
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+impl<T: DmaSafe> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        let size = self.frames as u64 * FRAME_SIZE;
+        // SAFETY: we own the mapping and the frames, and the borrow checker makes sure nothing references them anymore.
+        // The frames are freed separately, so unmapping must not free them.
+        unsafe {
+            if let Err(err) = paging::unmap_range(self.virt, size, false) {
+                warn!("Failed to unmap DMA buffer at {:#x}: {}", self.virt.as_u64(), err);
+            }
+            lock_once!(FRAME_ALLOCATOR).deallocate_contiguous(PhysFrame::containing_address(self.phys), self.frames);
+        }
+        let _ = region::free(self.virt);
+    }
+}
+
+/// Maps `frames` contiguous frames starting at `first` into a new region, and zeroes them.
+/// # Safety
+/// The frames must be owned by the caller.
+unsafe fn map(first: PhysFrame<Size4KiB>, frames: usize, cache: CacheMode) -> Result<VirtAddr, DmaError> {
+    let size = frames as u64 * FRAME_SIZE;
+    let region = region::allocate("dma buffer", RegionKind::Buffer, size, FRAME_SIZE)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache.flags();
+    for (i, page) in paging::pages_in_range(region.start, size).enumerate() {
+        if let Err(err) = unsafe { paging::map_page(page, first + i as u64, flags) } {
+            if i > 0 {
+                let _ = unsafe { paging::unmap_range(region.start, i as u64 * FRAME_SIZE, false) };
+            }
+            let _ = region::free(region.start);
+            return Err(err.into());
+        }
+    }
+    unsafe { ptr::write_bytes(region.start.as_mut_ptr::<u8>(), 0, size as usize) };
+    Ok(region.start)
+}
+
+/// Sets up the PAT so [`CacheMode::WriteCombining`] works.
+pub(super) fn init() {
+    let mut pat = Msr::new(IA32_PAT);
+    // Changing the PAT requires flushing the caches and the TLB, otherwise lines and translations cached with the old
+    // memory type stay around.
+    interrupts::without_interrupts(|| {
+        // SAFETY: every x86_64 CPU has a PAT, and entry 1 isn't used by any existing mapping. Write back and
+        // invalidate only flushes the caches, and toggling PGE only flushes the TLB.
+        unsafe {
+            asm!("wbinvd", options(nostack, preserves_flags));
+            let value = pat.read();
+            pat.write((value & !(0xff << 8)) | (PAT_WRITE_COMBINING << 8));
+            asm!("wbinvd", options(nostack, preserves_flags));
+            let cr4 = Cr4::read();
+            if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+                // Reloading CR3 keeps global pages, clearing PGE drops them as well.
+                Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+                Cr4::write(cr4);
+            } else {
+                tlb::flush_all();
+            }
+        }
+    });
+}
@@ -0,0 +1,243 @@
+//! Page table walking and mapping dumps for debugging.
+//!
+//! The walker reads the active page tables directly (through CR3 and the physical memory mapping) instead of
+//! locking [`OFFSET_PAGE_TABLE`](super::OFFSET_PAGE_TABLE), so it can be used from the page fault handler even
+//! if the fault happened while the page table was locked. Output goes to the serial port only.
+
+use core::fmt;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+use super::phys_to_virt;
+use crate::serial_println;
+
+/// The flags that are shown in dumps and that need to match for two mappings to be merged.
+const RELEVANT_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::HUGE_PAGE);
+
+/// A contiguous range of virtual memory mapped to contiguous physical memory with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    /// The size of the range in bytes.
+    pub size: u64,
+    /// The size of the pages the range is made of.
+    pub page_size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// Returns true if `next` directly continues this mapping.
+    fn continues_with(&self, next: &Mapping) -> bool {
+        self.virt.as_u64() + self.size == next.virt.as_u64()
+            && self.phys.as_u64() + self.size == next.phys.as_u64()
+            && self.page_size == next.page_size
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {:>5} {}",
+            self.virt.as_u64(),
+            self.virt.as_u64() + self.size,
+            self.phys.as_u64(),
+            PageSizeName(self.page_size),
+            FlagString(self.flags)
+        )
+    }
+}
+
+/// Formats a page size as 4K, 2M or 1G.
+struct PageSizeName(u64);
+
+impl fmt::Display for PageSizeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0x1000 => f.pad("4K"),
+            0x20_0000 => f.pad("2M"),
+            0x4000_0000 => f.pad("1G"),
+            size => write!(f, "{:#x}", size),
+        }
+    }
+}
+
+/// Formats page table flags as a short `rwxu` style string.
+pub struct FlagString(pub PageTableFlags);
+
+impl fmt::Display for FlagString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = self.0;
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}{}",
+            flag(flags.contains(PageTableFlags::PRESENT), 'r'),
+            flag(flags.contains(PageTableFlags::WRITABLE), 'w'),
+            flag(!flags.contains(PageTableFlags::NO_EXECUTE), 'x'),
+            flag(flags.contains(PageTableFlags::USER_ACCESSIBLE), 'u'),
+        )?;
+        if flags.contains(PageTableFlags::NO_EXECUTE) {
+            write!(f, " NX")?;
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            write!(f, " HUGE")?;
+        }
+        if flags.contains(PageTableFlags::NO_CACHE) {
+            write!(f, " UC")?;
+        }
+        if flags.contains(PageTableFlags::WRITE_THROUGH) {
+            write!(f, " WT")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sign extends a 48 bit address into a canonical virtual address.
+fn canonical(addr: u64) -> VirtAddr {
+    VirtAddr::new_truncate(addr)
+}
+
+/// Returns the page table at the given physical address.
+/// # Safety
+/// The address must point to a valid page table.
+unsafe fn table_at(addr: PhysAddr) -> &'static PageTable {
+    unsafe { &*phys_to_virt(addr).as_ptr::<PageTable>() }
+}
+
+/// Calls `f` for every mapped page (or huge page) in the active address space, in ascending virtual address order.
+/// The flags passed are the effective flags: a page is only writable or user accessible if every level allows it.
+pub fn walk(mut f: impl FnMut(Mapping)) {
+    let (l4_frame, _) = Cr3::read();
+    // SAFETY: CR3 always points to a valid level 4 table, and all lower level tables come from present entries.
+    let l4 = unsafe { table_at(l4_frame.start_address()) };
+    for (i4, e4) in l4.iter().enumerate() {
+        if !e4.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let l3 = unsafe { table_at(e4.addr()) };
+        for (i3, e3) in l3.iter().enumerate() {
+            if !e3.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let virt3 = ((i4 as u64) << 39) | ((i3 as u64) << 30);
+            if e3.flags().contains(PageTableFlags::HUGE_PAGE) {
+                f(mapping(virt3, e3.addr(), 0x4000_0000, &[e4.flags(), e3.flags()]));
+                continue;
+            }
+            let l2 = unsafe { table_at(e3.addr()) };
+            for (i2, e2) in l2.iter().enumerate() {
+                if !e2.flags().contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                let virt2 = virt3 | ((i2 as u64) << 21);
+                if e2.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    f(mapping(virt2, e2.addr(), 0x20_0000, &[e4.flags(), e3.flags(), e2.flags()]));
+                    continue;
+                }
+                let l1 = unsafe { table_at(e2.addr()) };
+                for (i1, e1) in l1.iter().enumerate() {
+                    if !e1.flags().contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+                    let virt1 = virt2 | ((i1 as u64) << 12);
+                    f(mapping(
+                        virt1,
+                        e1.addr(),
+                        0x1000,
+                        &[e4.flags(), e3.flags(), e2.flags(), e1.flags()],
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Builds a mapping from the flags of every level that leads to it.
+fn mapping(virt: u64, phys: PhysAddr, page_size: u64, levels: &[PageTableFlags]) -> Mapping {
+    let last = *levels.last().unwrap();
+    // Writable and user accessible have to be allowed by every level, while NX on any level applies to the whole range.
+    let mut flags = last & RELEVANT_FLAGS;
+    let all = |flag| levels.iter().all(|l| l.contains(flag));
+    flags.set(PageTableFlags::WRITABLE, all(PageTableFlags::WRITABLE));
+    flags.set(PageTableFlags::USER_ACCESSIBLE, all(PageTableFlags::USER_ACCESSIBLE));
+    flags.set(
+        PageTableFlags::NO_EXECUTE,
+        levels.iter().any(|l| l.contains(PageTableFlags::NO_EXECUTE)),
+    );
+    Mapping {
+        virt: canonical(virt),
+        phys,
+        size: page_size,
+        page_size,
+        flags,
+    }
+}
+
+/// Calls `f` for every coalesced range of mappings in the active address space.
+pub fn walk_coalesced(mut f: impl FnMut(Mapping)) {
+    let mut current: Option<Mapping> = None;
+    walk(|next| match current.as_mut() {
+        Some(range) if range.continues_with(&next) => range.size += next.size,
+        _ => {
+            if let Some(range) = current.replace(next) {
+                f(range);
+            }
+        }
+    });
+    if let Some(range) = current {
+        f(range);
+    }
+}
+
+/// Prints every coalesced mapping of the active address space to the serial port.
+pub fn dump_mappings() {
+    serial_println!("Page table mappings (CR3 = {:#x}):", Cr3::read().0.start_address().as_u64());
+    let mut ranges = 0;
+    walk_coalesced(|range| {
+        serial_println!("  {}", range);
+        ranges += 1;
+    });
+    serial_println!("{} mapped ranges", ranges);
+}
+
+/// Prints how the given address is translated, level by level, to the serial port.
+pub fn dump_address(addr: VirtAddr) {
+    serial_println!("Page table walk for {:#x}:", addr.as_u64());
+    let (l4_frame, _) = Cr3::read();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table_addr = l4_frame.start_address();
+    for (level, index) in indices.iter().enumerate() {
+        // SAFETY: the first table comes from CR3, and every following one from a present entry.
+        let table = unsafe { table_at(table_addr) };
+        let entry = &table[*index];
+        let flags = entry.flags();
+        serial_println!(
+            "  L{}[{:3}] = {:#014x} {}",
+            4 - level,
+            u16::from(*index),
+            entry.addr().as_u64(),
+            FlagString(flags)
+        );
+        if !flags.contains(PageTableFlags::PRESENT) {
+            serial_println!("  not mapped");
+            return;
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE) || level == 3 {
+            return;
+        }
+        table_addr = entry.addr();
+    }
+}
@@ -0,0 +1,243 @@
+//! Demand paging.
+//!
+//! Subsystems can register lazily backed virtual regions. Nothing is mapped when a region is registered;
+//! instead, the first access to each page causes a page fault, and [`handle_page_fault`] maps a zeroed frame
+//! there and lets the faulting instruction run again.
+//!
+//! The region table is a fixed size array so that resolving a fault never has to touch the heap.
+//!
+//! A page fault can interrupt code that holds the region table, the page table or the frame allocator, for example
+//! when a lazy page is touched while one of them is locked. Spinning on them would never end, so the fault handler
+//! only ever tries to take them, and reports the fault as fatal if one is held.
+
+use core::fmt;
+
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use super::{paging, phys_to_virt, MapError, FRAME_ALLOCATOR};
+use crate::{prelude::*, sync::IrqMutex};
+
+/// The maximum amount of lazy regions that can be registered at once.
+const MAX_LAZY_REGIONS: usize = 32;
+
+/// What a lazy region is used for. Only used for reporting, every kind is backed by zeroed frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyKind {
+    /// A stack that only gets backed as deep as it is actually used. Page faults run on a stack of their own, so
+    /// this can be the stack of the code that faults.
+    Stack,
+    /// Address space set aside for a heap to grow into.
+    HeapReservation,
+    /// A buffer that is filled with zeros on first touch.
+    ZeroFill,
+}
+
+/// A virtual region that gets backed by frames when it is first touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyRegion {
+    /// A name for the region, used in debug output.
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    /// The flags pages in this region are mapped with. `PRESENT` is always added.
+    pub flags: PageTableFlags,
+    pub kind: LazyKind,
+}
+
+impl LazyRegion {
+    /// Returns true if the address is inside of the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr.as_u64() < self.start.as_u64() + self.size
+    }
+
+    fn overlaps(&self, other: &LazyRegion) -> bool {
+        self.start.as_u64() < other.start.as_u64() + other.size
+            && other.start.as_u64() < self.start.as_u64() + self.size
+    }
+}
+
+/// An error returned when registering a lazy region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyRegionError {
+    /// The region overlaps with the contained, already registered, region.
+    Overlaps(&'static str),
+    /// The region table is full.
+    TableFull,
+    /// The region start or size isn't page aligned.
+    Unaligned,
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address is not part of any lazy region.
+    NotLazy,
+    /// The page is present, so the fault was a protection violation.
+    ProtectionViolation,
+    /// The access isn't allowed by the flags of the region it hit (for example a write to a read only region).
+    AccessDenied(&'static str),
+    /// Backing the page failed.
+    MapFailed(MapError),
+}
+
+/// The registered regions. Looked up by the page fault handler, so it keeps interrupts disabled while locked.
+static LAZY_REGIONS: IrqMutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> = IrqMutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Registers a lazily backed region.
+pub fn register_lazy_region(region: LazyRegion) -> Result<(), LazyRegionError> {
+    if !region.start.is_aligned(4096u64) || region.size % 4096 != 0 {
+        return Err(LazyRegionError::Unaligned);
+    }
+    let mut regions = LAZY_REGIONS.lock();
+    if let Some(other) = regions.iter().flatten().find(|r| r.overlaps(&region)) {
+        return Err(LazyRegionError::Overlaps(other.name));
+    }
+    let slot = regions
+        .iter_mut()
+        .find(|r| r.is_none())
+        .ok_or(LazyRegionError::TableFull)?;
+    *slot = Some(region);
+    debug!(
+        "Registered lazy region {} ({:?}) at {:#x}..{:#x}",
+        region.name,
+        region.kind,
+        region.start.as_u64(),
+        region.start.as_u64() + region.size
+    );
+    Ok(())
+}
+
+/// Removes the lazy region starting at `start`, unmapping and freeing every page that was faulted in.
+/// Returns the removed region.
+/// # Safety
+/// Nothing may use the region anymore.
+pub unsafe fn unregister_lazy_region(start: VirtAddr) -> Option<LazyRegion> {
+    let region = {
+        let mut regions = LAZY_REGIONS.lock();
+        regions
+            .iter_mut()
+            .find(|r| r.map_or(false, |r| r.start == start))?
+            .take()?
+    };
+    for page in paging::pages_in_range(region.start, region.size) {
+        // Pages that were never touched aren't mapped, so NotMapped is expected here.
+        let _ = unsafe { paging::unmap_range(page.start_address(), 4096, true) };
+    }
+    Some(region)
+}
+
+/// Returns the lazy region that contains the given address, if any.
+pub fn find_lazy_region(addr: VirtAddr) -> Option<LazyRegion> {
+    LAZY_REGIONS.lock().iter().flatten().find(|r| r.contains(addr)).copied()
+}
+
+/// Tries to resolve a page fault at `addr` by backing the page if it is part of a lazy region.
+/// Returns `Ok` if the faulting instruction can be restarted.
+///
+/// The fault may have interrupted code holding any lock, so locks are only taken with `try_lock`. A held lock fails
+/// with [`MapError::Locked`], see the module docs.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(FaultError::ProtectionViolation);
+    }
+    let region = LAZY_REGIONS
+        .try_lock()
+        .ok_or(FaultError::MapFailed(MapError::Locked("lazy region table")))?
+        .iter()
+        .flatten()
+        .find(|r| r.contains(addr))
+        .copied()
+        .ok_or(FaultError::NotLazy)?;
+
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return Err(FaultError::AccessDenied(region.name));
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && region.flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        return Err(FaultError::AccessDenied(region.name));
+    }
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return Err(FaultError::AccessDenied(region.name));
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let frame = FRAME_ALLOCATOR
+        .get()
+        .and_then(|allocator| allocator.try_lock())
+        .ok_or(FaultError::MapFailed(MapError::Locked("frame allocator")))?
+        .allocate_frame()
+        .ok_or(FaultError::MapFailed(MapError::OutOfFrames))?;
+    // Zero the frame through the physical memory mapping, so read only regions can be zero filled too.
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096);
+    }
+    // SAFETY: the frame is freshly allocated, and the page belongs to a registered lazy region.
+    unsafe { paging::try_map_page(page, frame, region.flags | PageTableFlags::PRESENT) }.map_err(|err| {
+        // The frame allocator was free a moment ago. If it isn't anymore the frame leaks, the fault is fatal anyway.
+        if let Some(mut allocator) = FRAME_ALLOCATOR.get().and_then(|allocator| allocator.try_lock()) {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+        FaultError::MapFailed(err)
+    })?;
+    trace!("Demand paged {:#x} in {}", page.start_address().as_u64(), region.name);
+    Ok(())
+}
+
+/// Explains a page fault error code in plain words.
+pub struct PageFaultDescription(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a present page (protection violation)"
+        } else {
+            "a non-present page"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} of {} in {} mode", access, page, mode)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table entry")?;
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            write!(f, ", protection key violation")?;
+        }
+        if code.contains(PageFaultErrorCode::SHADOW_STACK) {
+            write!(f, ", shadow stack access")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultError::NotLazy => write!(f, "address is not mapped"),
+            FaultError::ProtectionViolation => write!(f, "access not allowed by the page flags"),
+            FaultError::AccessDenied(name) => write!(f, "access not allowed in lazy region {}", name),
+            FaultError::MapFailed(err) => write!(f, "failed to back lazy page: {}", err),
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! A bitmap based physical frame allocator.
+//!
+//! The allocator is built from the memory map the bootloader hands us. Every 4KiB frame below the
+//! highest usable address gets a single bit in the bitmap, which is set when the frame is in use.
+//! The bitmap itself lives in the first usable region that is large enough to hold it, and is accessed
+//! through the physical memory offset mapping.
+
+use core::slice;
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{prelude::*, sync::IrqMutex};
+
+/// The size of a single frame.
+pub const FRAME_SIZE: u64 = 4096;
+
+/// How many frames a single bitmap word keeps track of.
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Frame counts sorted by what owns the frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// The total amount of frames tracked by the allocator.
+    pub total: usize,
+    /// Usable frames that are currently free.
+    pub free: usize,
+    /// Usable frames that are currently allocated.
+    pub used: usize,
+    /// Frames that are owned by the bootloader (page tables, boot info, kernel image).
+    pub bootloader: usize,
+    /// Frames that the firmware reserved, or that are not described by the memory map at all.
+    pub reserved: usize,
+}
+
+/// A physical frame allocator backed by a bitmap.
+pub struct BitmapFrameAllocator {
+    /// One bit per frame. A set bit means the frame can not be handed out.
+    bitmap: &'static mut [u64],
+    /// The amount of frames tracked by the bitmap.
+    frame_count: usize,
+    /// Where to start looking for the next free frame. Everything below this is known to be in use.
+    next_free: usize,
+    /// The frame counts, kept up to date on every allocation and deallocation.
+    stats: FrameStats,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates a new frame allocator from the bootloader memory map.
+    /// # Safety
+    /// The memory map must be valid, and all of physical memory must be mapped at `physical_memory_offset`.
+    /// Usable regions must not be in use by anything else, as the bitmap is placed in one of them.
+    pub unsafe fn new(regions: &[MemoryRegion], physical_memory_offset: VirtAddr) -> BitmapFrameAllocator {
+        // Only track memory that is actually backed by RAM. Firmware regions can sit at very high addresses (MMIO),
+        // and would make the bitmap huge for no reason.
+        let max_addr = regions
+            .iter()
+            .filter(|r| matches!(r.kind, MemoryRegionKind::Usable | MemoryRegionKind::Bootloader))
+            .map(|r| r.end)
+            .max()
+            .expect("Memory map has no usable memory!");
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (words * core::mem::size_of::<u64>()) as u64;
+
+        // Find a usable region to hold the bitmap.
+        let bitmap_region = regions
+            .iter()
+            .find(|r| r.kind == MemoryRegionKind::Usable && r.end - align_up(r.start) >= bitmap_bytes)
+            .expect("No usable region is large enough to hold the frame bitmap!");
+        let bitmap_start = align_up(bitmap_region.start);
+
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        // SAFETY: the region is usable, so nothing else is using it, and the caller guarantees the offset mapping.
+        let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_ptr, words) };
+        // Start out with everything marked as used, and then free the usable regions.
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            next_free: 0,
+            stats: FrameStats {
+                total: frame_count,
+                ..FrameStats::default()
+            },
+        };
+
+        for region in regions.iter() {
+            let start = (align_up(region.start) / FRAME_SIZE) as usize;
+            let end = ((region.end / FRAME_SIZE) as usize).min(frame_count);
+            if start >= end {
+                continue;
+            }
+            match region.kind {
+                MemoryRegionKind::Usable => {
+                    for frame in start..end {
+                        allocator.clear_bit(frame);
+                    }
+                    allocator.stats.free += end - start;
+                }
+                MemoryRegionKind::Bootloader => allocator.stats.bootloader += end - start,
+                _ => {}
+            }
+        }
+        // Everything that wasn't described as usable or bootloader memory counts as reserved.
+        allocator.stats.reserved = frame_count - allocator.stats.free - allocator.stats.bootloader;
+
+        // Mark the frames used by the bitmap itself.
+        let bitmap_frames = (align_up(bitmap_bytes) / FRAME_SIZE) as usize;
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for frame in bitmap_first..bitmap_first + bitmap_frames {
+            allocator.set_bit(frame);
+        }
+        allocator.stats.free -= bitmap_frames;
+        allocator.stats.used += bitmap_frames;
+
+        info!(
+            "Frame allocator: {} frames tracked, bitmap at {:#x} ({} bytes)",
+            frame_count, bitmap_start, bitmap_bytes
+        );
+        allocator
+    }
+
+    /// Returns the current frame counts.
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Returns the amount of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.stats.free
+    }
+
+    /// Returns the amount of allocated frames.
+    pub fn used_frames(&self) -> usize {
+        self.stats.used
+    }
+
+    /// Returns true if the frame at the given index is in use (or not usable at all).
+    fn is_set(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+    }
+
+    /// Finds the index of the first free frame at or after `next_free`.
+    fn find_free(&self) -> Option<usize> {
+        let first_word = self.next_free / BITS_PER_WORD;
+        for (word_idx, word) in self.bitmap.iter().enumerate().skip(first_word) {
+            // Skip full words without looking at every bit.
+            if *word == u64::MAX {
+                continue;
+            }
+            let frame = word_idx * BITS_PER_WORD + word.trailing_ones() as usize;
+            if frame < self.frame_count {
+                return Some(frame);
+            }
+        }
+        None
+    }
+}
+
+impl BitmapFrameAllocator {
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// The first frame is aligned to `align` bytes, the whole range ends at or below `max_addr`, and if `boundary` is set
+    /// the range doesn't cross a multiple of it (ISA DMA for example can't cross a 64KiB boundary).
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: u64,
+        max_addr: u64,
+        boundary: Option<u64>,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        if count == 0 {
+            return None;
+        }
+        let step = (align.max(FRAME_SIZE) / FRAME_SIZE) as usize;
+        let limit = ((max_addr / FRAME_SIZE) as usize).min(self.frame_count);
+        // Start at the first aligned frame that could be free.
+        let mut start = (self.next_free + step - 1) / step * step;
+        while start + count <= limit {
+            let start_addr = start as u64 * FRAME_SIZE;
+            let end_addr = start_addr + count as u64 * FRAME_SIZE;
+            if let Some(boundary) = boundary {
+                if start_addr / boundary != (end_addr - 1) / boundary {
+                    start += step;
+                    continue;
+                }
+            }
+            match (start..start + count).find(|frame| self.is_set(*frame)) {
+                // Skip past the used frame, everything in between can't start a free run.
+                Some(used) => start = (used + 1 + step - 1) / step * step,
+                None => {
+                    for frame in start..start + count {
+                        self.set_bit(frame);
+                    }
+                    self.stats.free -= count;
+                    self.stats.used += count;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(start_addr)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees `count` contiguous frames starting at `first`.
+    /// # Safety
+    /// The frames must have been allocated by this allocator, and must not be in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame<Size4KiB>, count: usize) {
+        for i in 0..count as u64 {
+            unsafe { self.deallocate_frame(first + i) };
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.find_free()?;
+        self.set_bit(frame);
+        self.next_free = frame + 1;
+        self.stats.free -= 1;
+        self.stats.used += 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let idx = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(idx < self.frame_count, "Deallocated frame {:?} is outside of tracked memory!", frame);
+        assert!(self.is_set(idx), "Double free of frame {:?}!", frame);
+        self.clear_bit(idx);
+        self.next_free = self.next_free.min(idx);
+        self.stats.free += 1;
+        self.stats.used -= 1;
+    }
+}
+
+/// Aligns the given address up to the next frame boundary.
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+/// The global frame allocator. It's used by the page fault handler, so it keeps interrupts disabled while locked.
+pub static FRAME_ALLOCATOR: OnceCell<IrqMutex<BitmapFrameAllocator>> = OnceCell::uninit();
+
+/// Initializes the global frame allocator from the bootloader memory map.
+/// # Safety
+/// See [`BitmapFrameAllocator::new`].
+pub(super) unsafe fn init(regions: &[MemoryRegion], physical_memory_offset: VirtAddr) {
+    FRAME_ALLOCATOR
+        .try_init_once(|| IrqMutex::new(unsafe { BitmapFrameAllocator::new(regions, physical_memory_offset) }))
+        .expect("Frame allocator already initialized!");
+}
+
+/// Allocates a single frame from the global frame allocator.
+pub fn allocate_frame() -> Option<PhysFrame<Size4KiB>> {
+    crate::lock_once!(FRAME_ALLOCATOR).allocate_frame()
+}
+
+/// Returns a frame to the global frame allocator.
+/// # Safety
+/// The frame must have been allocated by the frame allocator, and must not be in use anymore.
+pub unsafe fn deallocate_frame(frame: PhysFrame<Size4KiB>) {
+    unsafe { crate::lock_once!(FRAME_ALLOCATOR).deallocate_frame(frame) }
+}
+
+/// Returns the current frame counts of the global frame allocator.
+pub fn frame_stats() -> FrameStats {
+    crate::lock_once!(FRAME_ALLOCATOR).stats()
+}
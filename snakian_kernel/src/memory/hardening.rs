@@ -0,0 +1,274 @@
+//! Kernel memory hardening.
+//!
+//! Turns on the CPU protection features the processor supports, tightens the permissions of the kernel's own
+//! mappings, and audits the page tables for anything that is still writable and executable at the same time:
+//! - `EFER.NXE` makes the `NO_EXECUTE` page flag work at all. It is enabled first thing in [`super::init`],
+//!   since every mapping the kernel makes uses it.
+//! - `CR0.WP` makes read only pages read only for the kernel as well.
+//! - `CR4.SMEP` and `CR4.SMAP` stop the kernel from executing or touching user accessible pages.
+//!
+//! The kernel sections are found through the program headers of the kernel ELF, which the bootloader leaves in memory:
+//! text ends up RX, read only data R and everything else RW+NX.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+use bootloader_api::BootInfo;
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+use super::{
+    dump, paging, phys_to_virt,
+    region::{self, RegionKind},
+    OFFSET_PAGE_TABLE,
+};
+use crate::{lock_once, prelude::*};
+
+const PAGE_SIZE: u64 = 4096;
+/// The amount of virtual memory covered by a single level 4 entry.
+const L4_ENTRY_SIZE: u64 = 1 << 39;
+/// The maximum amount of ELF program headers looked at.
+const MAX_SEGMENTS: usize = 16;
+
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// The CPU protection features that ended up enabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Protections {
+    pub nx: bool,
+    pub write_protect: bool,
+    pub smep: bool,
+    pub smap: bool,
+}
+
+/// A loadable kernel segment, already relocated to its virtual address.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    kind: u32,
+    start: u64,
+    end: u64,
+    flags: u32,
+}
+
+/// Enables no-execute support, if the CPU has it. Returns true if it is enabled.
+pub fn enable_nx() -> bool {
+    // SAFETY: cpuid is always available in long mode.
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0001 || unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) == 0 {
+        return false;
+    }
+    // SAFETY: enabling NXE only gives meaning to a page table bit that would otherwise be reserved.
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    true
+}
+
+/// Enables write protection and, if the CPU supports them, SMEP and SMAP.
+fn enable_protections() -> Protections {
+    let mut protections = Protections {
+        nx: Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE),
+        ..Protections::default()
+    };
+    // SAFETY: the kernel doesn't write to read only pages, and it never touches user pages.
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+    protections.write_protect = true;
+
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 7 {
+        let features = unsafe { __cpuid_count(7, 0) }.ebx;
+        protections.smep = features & (1 << 7) != 0;
+        protections.smap = features & (1 << 20) != 0;
+    }
+    let mut cr4 = Cr4Flags::empty();
+    cr4.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, protections.smep);
+    cr4.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, protections.smap);
+    unsafe { Cr4::update(|flags| flags.insert(cr4)) };
+    protections
+}
+
+/// Runs the hardening stage: enables the CPU protections, remaps the kernel sections and audits the page tables.
+/// # Safety
+/// The boot info must come from the bootloader, and the memory module must be initialized.
+pub unsafe fn harden(boot_info: &BootInfo) -> Protections {
+    let protections = enable_protections();
+    info!(
+        "Memory protections: NX {}, WP {}, SMEP {}, SMAP {}",
+        protections.nx, protections.write_protect, protections.smep, protections.smap
+    );
+    // Without NXE the NO_EXECUTE bit is reserved, so only the audit can run.
+    if protections.nx {
+        if let Err(err) = unsafe { remap_kernel(boot_info) } {
+            warn!("Failed to remap the kernel sections: {}", err);
+        }
+        unsafe { protect_physical_map() };
+    }
+    let found = audit();
+    if found == 0 {
+        info!("W^X audit passed");
+    } else {
+        warn!("W^X audit found {} writable and executable ranges", found);
+    }
+    protections
+}
+
+/// Reads the program headers of the kernel ELF.
+/// # Safety
+/// `kernel_addr` must be the physical address of the kernel ELF.
+unsafe fn kernel_segments(boot_info: &BootInfo) -> ([Option<Segment>; MAX_SEGMENTS], usize) {
+    let elf = phys_to_virt(x86_64::PhysAddr::new(boot_info.kernel_addr)).as_ptr::<u8>();
+    let read_u16 = |offset: u64| unsafe { elf.add(offset as usize).cast::<u16>().read_unaligned() };
+    let read_u32 = |offset: u64| unsafe { elf.add(offset as usize).cast::<u32>().read_unaligned() };
+    let read_u64 = |offset: u64| unsafe { elf.add(offset as usize).cast::<u64>().read_unaligned() };
+
+    // Position independent kernels are relocated by the image offset, others are loaded where they are linked.
+    let load_offset = if read_u16(16) == ET_DYN {
+        boot_info.kernel_image_offset
+    } else {
+        0
+    };
+    let phoff = read_u64(32);
+    let phentsize = read_u16(54) as u64;
+    let phnum = read_u16(56) as usize;
+
+    let mut segments = [None; MAX_SEGMENTS];
+    let mut count = 0;
+    for i in 0..phnum {
+        let header = phoff + i as u64 * phentsize;
+        let kind = read_u32(header);
+        if kind != PT_LOAD && kind != PT_GNU_RELRO {
+            continue;
+        }
+        if count == MAX_SEGMENTS {
+            warn!("Kernel has more than {} segments, ignoring the rest", MAX_SEGMENTS);
+            break;
+        }
+        let start = load_offset + read_u64(header + 16);
+        segments[count] = Some(Segment {
+            kind,
+            start,
+            end: start + read_u64(header + 40),
+            flags: read_u32(header + 4),
+        });
+        count += 1;
+    }
+    (segments, count)
+}
+
+/// The end of the loaded kernel image in virtual memory: the highest end of a loadable segment, which includes the
+/// zero filled part of segments like `.bss` that isn't in the ELF file.
+/// # Safety
+/// See [`kernel_segments`].
+pub(super) unsafe fn kernel_image_end(boot_info: &BootInfo) -> Option<u64> {
+    let (segments, count) = unsafe { kernel_segments(boot_info) };
+    segments[..count].iter().flatten().filter(|s| s.kind == PT_LOAD).map(|s| s.end).max()
+}
+
+/// Gives every page of the kernel image the permissions of the segments it belongs to.
+/// # Safety
+/// See [`harden`].
+unsafe fn remap_kernel(boot_info: &BootInfo) -> Result<(), paging::MapError> {
+    let (segments, count) = unsafe { kernel_segments(boot_info) };
+    let segments = &segments[..count];
+    for segment in segments.iter().flatten().filter(|s| s.kind == PT_LOAD) {
+        let start = VirtAddr::new(segment.start).align_down(PAGE_SIZE);
+        let size = VirtAddr::new(segment.end).align_up(PAGE_SIZE) - start;
+        // SAFETY: pages only lose permissions their segments don't ask for.
+        unsafe {
+            paging::modify_flags(start, size, |page, flags| {
+                let page_start = page.start_address().as_u64();
+                let page_end = page_start + PAGE_SIZE;
+                // A page shared by two segments needs the permissions of both.
+                let covering = || {
+                    segments
+                        .iter()
+                        .flatten()
+                        .filter(move |s| s.kind == PT_LOAD && s.start < page_end && page_start < s.end)
+                };
+                let writable = covering().any(|s| s.flags & PF_W != 0)
+                    && !segments
+                        .iter()
+                        .flatten()
+                        .any(|s| s.kind == PT_GNU_RELRO && s.start <= page_start && page_end <= s.end);
+                let executable = covering().any(|s| s.flags & PF_X != 0);
+
+                let mut flags = flags;
+                flags.set(PageTableFlags::WRITABLE, writable);
+                flags.set(PageTableFlags::NO_EXECUTE, !executable);
+                flags
+            })?;
+        }
+        debug!(
+            "Kernel segment {:#x}..{:#x} is r{}{}",
+            segment.start,
+            segment.end,
+            if segment.flags & PF_W != 0 { 'w' } else { '-' },
+            if segment.flags & PF_X != 0 { 'x' } else { '-' }
+        );
+    }
+    Ok(())
+}
+
+/// Sets `NO_EXECUTE` on the level 4 entries of the physical memory mapping, so none of it can be executed.
+/// Entries that also contain other regions are left alone.
+/// # Safety
+/// Nothing may execute code through the physical memory mapping.
+unsafe fn protect_physical_map() {
+    let mut physical_map = None;
+    region::for_each_region(|r| {
+        if r.kind == RegionKind::PhysicalMemoryMap {
+            physical_map = Some(*r);
+        }
+    });
+    let Some(physical_map) = physical_map else {
+        return;
+    };
+    let first = physical_map.start.as_u64() / L4_ENTRY_SIZE;
+    let last = (physical_map.end().as_u64() - 1) / L4_ENTRY_SIZE;
+
+    let mut mapper = lock_once!(OFFSET_PAGE_TABLE);
+    let l4 = mapper.level_4_table();
+    for index in first..=last {
+        let (entry_start, entry_end) = (index * L4_ENTRY_SIZE, (index + 1) * L4_ENTRY_SIZE);
+        let mut shared = false;
+        region::for_each_region(|r| {
+            shared |= r.kind != RegionKind::PhysicalMemoryMap
+                && r.start.as_u64() < entry_end
+                && entry_start < r.end().as_u64();
+        });
+        if shared {
+            warn!("Physical memory mapping shares L4 entry {} with other regions, leaving it executable", index);
+            continue;
+        }
+        let entry = &mut l4[(index % 512) as usize];
+        entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+    }
+    tlb::flush_all();
+}
+
+/// Logs every range of the active address space that is both writable and executable.
+/// Returns the amount of ranges found.
+pub fn audit() -> usize {
+    let mut found = 0;
+    dump::walk_coalesced(|mapping| {
+        if mapping.flags.contains(PageTableFlags::WRITABLE) && !mapping.flags.contains(PageTableFlags::NO_EXECUTE) {
+            let owner = region::find(mapping.virt).map_or("unknown", |r| r.name);
+            warn!(
+                "W^X violation: {:#x}..{:#x} is writable and executable ({})",
+                mapping.virt.as_u64(),
+                mapping.virt.as_u64() + mapping.size,
+                owner
+            );
+            found += 1;
+        }
+    });
+    found
+}
@@ -0,0 +1,104 @@
+//! The kernel heap.
+//!
+//! The heap is a fixed virtual range that gets backed by freshly allocated frames during memory initialization.
+//! Once it is set up, everything in the `alloc` crate (Vec, Box, String, etc) can be used.
+//!
+//! With the `heap-debug` feature the allocator is wrapped in a [`DebugAllocator`](super::allocator::debug::DebugAllocator),
+//! and [`leak_mark`] and [`report_leaks_since`] can be used to find allocations that are never freed.
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use super::{
+    allocator::{AllocatorStats, HeapStats, Locked},
+    paging::{map_range, MapError},
+};
+#[cfg(feature = "heap-debug")]
+use super::allocator::{
+    debug::{self, AllocationInfo, DebugAllocator},
+    RawAllocator,
+};
+
+// The linked list allocator is the default, so an explicitly enabled allocator takes precedence over it.
+#[cfg(all(feature = "allocator-bump", feature = "allocator-fixed-block"))]
+compile_error!("Only one of allocator-bump and allocator-fixed-block can be enabled at a time!");
+
+#[cfg(not(any(
+    feature = "allocator-bump",
+    feature = "allocator-linked-list",
+    feature = "allocator-fixed-block"
+)))]
+compile_error!("No heap allocator feature is enabled! Enable one of allocator-bump, allocator-linked-list or allocator-fixed-block.");
+
+#[cfg(feature = "allocator-bump")]
+type HeapAllocator = super::allocator::bump::BumpAllocator;
+#[cfg(all(feature = "allocator-fixed-block", not(feature = "allocator-bump")))]
+type HeapAllocator = super::allocator::fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(all(
+    feature = "allocator-linked-list",
+    not(any(feature = "allocator-bump", feature = "allocator-fixed-block"))
+))]
+type HeapAllocator = super::allocator::linked_list::LinkedListAllocator;
+
+/// The name of the allocator strategy this kernel was built with.
+#[cfg(feature = "allocator-bump")]
+pub const ALLOCATOR_NAME: &str = "bump";
+#[cfg(all(feature = "allocator-fixed-block", not(feature = "allocator-bump")))]
+pub const ALLOCATOR_NAME: &str = "fixed-size-block";
+#[cfg(all(
+    feature = "allocator-linked-list",
+    not(any(feature = "allocator-bump", feature = "allocator-fixed-block"))
+))]
+pub const ALLOCATOR_NAME: &str = "linked-list";
+
+/// The virtual address the heap starts at. Picked to be easily recognizable in a debugger.
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// The size of the heap. 4 MiB.
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+/// The most leaks [`report_leaks_since`] lists one by one.
+#[cfg(feature = "heap-debug")]
+pub const LEAK_REPORT_LIMIT: usize = 32;
+
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+static ALLOCATOR: Locked<HeapAllocator> = Locked::new(HeapAllocator::new());
+
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static ALLOCATOR: Locked<DebugAllocator<HeapAllocator>> = Locked::new(DebugAllocator::new(HeapAllocator::new()));
+
+/// Maps the heap region and initializes the global allocator.
+pub(super) fn init_heap() -> Result<(), MapError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    map_range(VirtAddr::new(HEAP_START as u64), HEAP_SIZE as u64, flags)?;
+
+    // SAFETY: the heap range was just mapped, and this is only called once during memory initialization.
+    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) };
+    Ok(())
+}
+
+/// Returns the current statistics of the kernel heap allocator.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Returns a marker for the current point in time, to be passed to [`report_leaks_since`].
+#[cfg(feature = "heap-debug")]
+pub fn leak_mark() -> u64 {
+    ALLOCATOR.lock().mark()
+}
+
+/// Prints every allocation made since `mark` that is still alive to the serial port, and returns how many there are.
+#[cfg(feature = "heap-debug")]
+pub fn report_leaks_since(mark: u64) -> usize {
+    // Printing happens after the allocator is unlocked again.
+    let mut leaks = [None; LEAK_REPORT_LIMIT];
+    let count = allocations_since(mark, &mut leaks);
+    debug::report(&leaks, count);
+    count
+}
+
+/// Copies the live allocations made since `mark` into `out`, newest first, and returns how many there are.
+#[cfg(feature = "heap-debug")]
+pub fn allocations_since(mark: u64, out: &mut [Option<AllocationInfo>]) -> usize {
+    ALLOCATOR.lock().collect_since(mark, out)
+}
@@ -1,3 +1,4 @@
+use bootloader_api::BootInfo;
 use conquer_once::spin::OnceCell;
 use x86_64::{
     registers::control::Cr3,
@@ -5,20 +6,109 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
-use spin::Mutex;
+use crate::{lock_once, prelude::*, sync::IrqMutex};
 
-use crate::lock_once;
+pub mod allocator;
+pub mod dma;
+pub mod dump;
+pub mod fault;
+pub mod frame_allocator;
+pub mod hardening;
+pub mod heap;
+pub mod paging;
+pub mod region;
+pub mod stack;
 
-pub static OFFSET_PAGE_TABLE: OnceCell<Mutex<OffsetPageTable>> = OnceCell::uninit();
+pub use frame_allocator::{allocate_frame, deallocate_frame, frame_stats, FrameStats, FRAME_ALLOCATOR};
+pub use paging::{change_flags, identity_map_mmio, map_page, map_range, unmap_range, MapError};
+
+/// The active page table. It's used by the page fault handler, so it keeps interrupts disabled while locked.
+pub static OFFSET_PAGE_TABLE: OnceCell<IrqMutex<OffsetPageTable>> = OnceCell::uninit();
+/// The virtual address all of physical memory is mapped at.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
 /// Initializes the memory module. This function should be called before any other memory functions.
 /// This function has no dependencies, so it can be called at the start of kernel initialization.
-pub unsafe fn init(physical_memory_offset: u64){
+/// # Safety
+/// The boot info must come from the bootloader, with all of physical memory mapped at its physical memory offset.
+pub unsafe fn init(boot_info: &BootInfo) {
+    let physical_memory_offset = boot_info
+        .physical_memory_offset
+        .into_option()
+        .expect("Physical memory is not mapped!");
+    // Every mapping made from here on uses NO_EXECUTE, which is a reserved bit unless NXE is on.
+    if !hardening::enable_nx() {
+        warn!("CPU doesn't support no-execute pages!");
+    }
+    PHYSICAL_MEMORY_OFFSET.init_once(|| VirtAddr::new(physical_memory_offset));
     OFFSET_PAGE_TABLE.init_once(|| {
         let level_4_table = unsafe { get_l4_table(VirtAddr::new(physical_memory_offset)) };
-        Mutex::new(unsafe {
+        IrqMutex::new(unsafe {
             OffsetPageTable::new(level_4_table, VirtAddr::new(physical_memory_offset))
         })
     });
+    unsafe { frame_allocator::init(&boot_info.memory_regions, VirtAddr::new(physical_memory_offset)) };
+    heap::init_heap().expect("Failed to initialize the kernel heap!");
+    reserve_boot_regions(boot_info);
+    dma::init();
+}
+
+/// Reserves the virtual ranges the bootloader set up, so the region manager knows about them.
+fn reserve_boot_regions(boot_info: &BootInfo) {
+    use region::{reserve, RegionKind};
+
+    region::check_window();
+    let phys_end = boot_info.memory_regions.iter().map(|r| r.end).max().unwrap_or(0);
+    // `kernel_len` is the size of the ELF file, the image in memory is larger by the zero filled segments.
+    // SAFETY: the bootloader passes the physical address of the kernel ELF.
+    let kernel_size = unsafe { hardening::kernel_image_end(boot_info) }
+        .map_or(boot_info.kernel_len, |end| end.saturating_sub(boot_info.kernel_image_offset));
+    let reservations = [
+        (
+            "physical memory",
+            RegionKind::PhysicalMemoryMap,
+            *PHYSICAL_MEMORY_OFFSET.get().unwrap(),
+            phys_end,
+        ),
+        (
+            "kernel image",
+            RegionKind::KernelImage,
+            VirtAddr::new(boot_info.kernel_image_offset),
+            kernel_size,
+        ),
+        (
+            "kernel heap",
+            RegionKind::Heap,
+            VirtAddr::new(heap::HEAP_START as u64),
+            heap::HEAP_SIZE as u64,
+        ),
+        (
+            "boot info",
+            RegionKind::BootInfo,
+            VirtAddr::from_ptr(boot_info),
+            core::mem::size_of::<BootInfo>() as u64,
+        ),
+    ];
+    for (name, kind, start, size) in reservations {
+        if let Err(err) = reserve(name, kind, start, size) {
+            warn!("Failed to reserve the {} region: {:?}", name, err);
+        }
+    }
+    if let Some(ramdisk) = boot_info.ramdisk_addr.into_option() {
+        if let Err(err) = reserve("ramdisk", RegionKind::Other, VirtAddr::new(ramdisk), boot_info.ramdisk_len) {
+            warn!("Failed to reserve the ramdisk region: {:?}", err);
+        }
+    }
+    if let Some(framebuffer) = boot_info.framebuffer.as_ref() {
+        let buffer = framebuffer.buffer();
+        if let Err(err) = reserve(
+            "framebuffer",
+            RegionKind::Framebuffer,
+            VirtAddr::from_ptr(buffer.as_ptr()),
+            buffer.len() as u64,
+        ) {
+            warn!("Failed to reserve the framebuffer region: {:?}", err);
+        }
+    }
 }
 
 /// Gets the active level 4 page table.
@@ -31,6 +121,14 @@ unsafe fn get_l4_table(phys_mem_offset: VirtAddr) -> &'static mut PageTable {
     unsafe { &mut *pg_tbl_ptr }
 }
 
+/// Returns the virtual address the given physical address can be accessed at through the physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("Memory module not initialized!");
+    *offset + addr.as_u64()
+}
+
 /// Translates a virtual address to a physical address. If the address is not mapped, this function returns None.
 pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
     let offset_page_table = lock_once!(OFFSET_PAGE_TABLE);
@@ -0,0 +1,259 @@
+//! Functions for mapping and unmapping pages in the active page table.
+//!
+//! Everything here goes through [`OFFSET_PAGE_TABLE`] and the global frame allocator, so callers never have to lock
+//! the page table themselves. Errors are reported as a [`MapError`] instead of panicking.
+
+use core::fmt;
+
+use x86_64::{
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{FRAME_ALLOCATOR, OFFSET_PAGE_TABLE};
+use crate::{lock_once, prelude::*};
+
+/// An error that occurred while changing the page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is already mapped to the contained frame.
+    AlreadyMapped { page: VirtAddr, frame: PhysAddr },
+    /// The page is not mapped.
+    NotMapped(VirtAddr),
+    /// The frame allocator has no frames left.
+    OutOfFrames,
+    /// The address is part of a huge page, so a 4KiB page can't be mapped, unmapped or changed there.
+    HugePageConflict(VirtAddr),
+    /// The page table entry points to an invalid physical address.
+    InvalidFrame(VirtAddr),
+    /// The page is already mapped to the right frame, but caching is enabled for it.
+    Cached(VirtAddr),
+    /// The contained lock was held by the code that was interrupted.
+    Locked(&'static str),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::AlreadyMapped { page, frame } => {
+                write!(f, "page {:#x} is already mapped to {:#x}", page.as_u64(), frame.as_u64())
+            }
+            MapError::NotMapped(page) => write!(f, "page {:#x} is not mapped", page.as_u64()),
+            MapError::OutOfFrames => write!(f, "out of physical frames"),
+            MapError::HugePageConflict(addr) => write!(f, "{:#x} is part of a huge page", addr.as_u64()),
+            MapError::InvalidFrame(page) => {
+                write!(f, "page {:#x} points to an invalid frame", page.as_u64())
+            }
+            MapError::Cached(page) => write!(f, "page {:#x} is already mapped with caching", page.as_u64()),
+            MapError::Locked(lock) => write!(f, "the {} is locked", lock),
+        }
+    }
+}
+
+impl MapError {
+    fn from_map_to(err: MapToError<Size4KiB>, page: Page) -> MapError {
+        match err {
+            MapToError::FrameAllocationFailed => MapError::OutOfFrames,
+            MapToError::ParentEntryHugePage => MapError::HugePageConflict(page.start_address()),
+            MapToError::PageAlreadyMapped(frame) => MapError::AlreadyMapped {
+                page: page.start_address(),
+                frame: frame.start_address(),
+            },
+        }
+    }
+
+    fn from_unmap(err: UnmapError, page: Page) -> MapError {
+        match err {
+            UnmapError::ParentEntryHugePage => MapError::HugePageConflict(page.start_address()),
+            UnmapError::PageNotMapped => MapError::NotMapped(page.start_address()),
+            UnmapError::InvalidFrameAddress(_) => MapError::InvalidFrame(page.start_address()),
+        }
+    }
+
+    fn from_flag_update(err: FlagUpdateError, page: Page) -> MapError {
+        match err {
+            FlagUpdateError::PageNotMapped => MapError::NotMapped(page.start_address()),
+            FlagUpdateError::ParentEntryHugePage => MapError::HugePageConflict(page.start_address()),
+        }
+    }
+}
+
+/// Returns all 4KiB pages that overlap the range `start..start + size`.
+pub fn pages_in_range(start: VirtAddr, size: u64) -> impl Iterator<Item = Page> {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + size.max(1) - 1u64);
+    Page::range_inclusive(first, last)
+}
+
+/// Maps the given page to the given frame.
+/// # Safety
+/// Mapping a frame that is already in use somewhere else, or remapping memory the kernel relies on, breaks memory safety.
+pub unsafe fn map_page(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+    let mut mapper = lock_once!(OFFSET_PAGE_TABLE);
+    let mut frame_allocator = lock_once!(FRAME_ALLOCATOR);
+    unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }
+        .map_err(|err| MapError::from_map_to(err, page))?
+        .flush();
+    Ok(())
+}
+
+/// Like [`map_page`], but fails with [`MapError::Locked`] instead of spinning if the page table or the frame allocator
+/// is held. Meant for the page fault handler, which can interrupt code holding either of them.
+/// # Safety
+/// See [`map_page`].
+pub(super) unsafe fn try_map_page(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+    let mut mapper = OFFSET_PAGE_TABLE
+        .get()
+        .and_then(|mapper| mapper.try_lock())
+        .ok_or(MapError::Locked("page table"))?;
+    let mut frame_allocator = FRAME_ALLOCATOR
+        .get()
+        .and_then(|allocator| allocator.try_lock())
+        .ok_or(MapError::Locked("frame allocator"))?;
+    unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }
+        .map_err(|err| MapError::from_map_to(err, page))?
+        .flush();
+    Ok(())
+}
+
+/// Maps every page in the range `start..start + size` to a freshly allocated frame.
+/// If anything goes wrong, the pages that were already mapped are unmapped again and their frames are freed.
+pub fn map_range(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    let mut mapper = lock_once!(OFFSET_PAGE_TABLE);
+    let mut frame_allocator = lock_once!(FRAME_ALLOCATOR);
+    let mut mapped = 0;
+    let mut result = Ok(());
+    for page in pages_in_range(start, size) {
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            result = Err(MapError::OutOfFrames);
+            break;
+        };
+        // SAFETY: the frame was just allocated, so nothing else is using it.
+        match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                result = Err(MapError::from_map_to(err, page));
+                break;
+            }
+        }
+        mapped += 1;
+    }
+    if result.is_err() {
+        // Roll back everything that was mapped before the failure.
+        for page in pages_in_range(start, size).take(mapped) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    }
+    result
+}
+
+/// Unmaps every page in the range `start..start + size`.
+/// If `free_frames` is true, the frames the pages pointed to are given back to the frame allocator.
+/// # Safety
+/// Nothing may use the range anymore. If `free_frames` is set, the frames must have come from the frame allocator.
+pub unsafe fn unmap_range(start: VirtAddr, size: u64, free_frames: bool) -> Result<(), MapError> {
+    let mut mapper = lock_once!(OFFSET_PAGE_TABLE);
+    let mut frame_allocator = lock_once!(FRAME_ALLOCATOR);
+    for page in pages_in_range(start, size) {
+        let (frame, flush) = mapper.unmap(page).map_err(|err| MapError::from_unmap(err, page))?;
+        flush.flush();
+        if free_frames {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the flags of every page in the range `start..start + size`.
+/// # Safety
+/// Changing the flags of memory that is in use (for example making it non writable) can break memory safety.
+pub unsafe fn change_flags(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    let mut mapper = lock_once!(OFFSET_PAGE_TABLE);
+    for page in pages_in_range(start, size) {
+        unsafe { mapper.update_flags(page, flags) }
+            .map_err(|err| MapError::from_flag_update(err, page))?
+            .flush();
+    }
+    Ok(())
+}
+
+/// Identity maps the physical range `phys..phys + size` as uncached device memory, and returns its virtual address.
+/// Pages that are already identity mapped without caching are left alone, so the same MMIO window can be mapped more
+/// than once. An existing identity mapping that is cached is an error, device registers must never be cached.
+/// If anything goes wrong, the pages this call mapped are unmapped again.
+/// # Safety
+/// The range must be device memory (or otherwise not in use by the kernel).
+pub unsafe fn identity_map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let virt = VirtAddr::new(phys.as_u64());
+    // Only the pages mapped here are rolled back, the ones that were already there belong to someone else.
+    let mut mapped = Vec::new();
+    let mut result = Ok(virt);
+    for page in pages_in_range(virt, size) {
+        let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64()));
+        match unsafe { map_page(page, frame, flags) } {
+            Ok(()) => mapped.push(page),
+            Err(MapError::AlreadyMapped { frame: existing, .. }) if existing == frame.start_address() => {
+                let uncached = match lock_once!(OFFSET_PAGE_TABLE).translate(page.start_address()) {
+                    TranslateResult::Mapped { flags, .. } => flags.contains(PageTableFlags::NO_CACHE),
+                    _ => false,
+                };
+                if !uncached {
+                    result = Err(MapError::Cached(page.start_address()));
+                    break;
+                }
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    if result.is_err() {
+        // The frames are device memory, so they don't go back to the frame allocator.
+        let mut mapper = lock_once!(OFFSET_PAGE_TABLE);
+        for page in mapped {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    }
+    result
+}
+
+/// Changes the flags of every page in `start..start + size` to whatever `f` returns for the page and its current flags.
+/// # Safety
+/// The new flags must not break anything that uses the pages.
+pub unsafe fn modify_flags(
+    start: VirtAddr,
+    size: u64,
+    mut f: impl FnMut(Page, PageTableFlags) -> PageTableFlags,
+) -> Result<(), MapError> {
+    let mut mapper = lock_once!(OFFSET_PAGE_TABLE);
+    for page in pages_in_range(start, size) {
+        let flags = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                flags,
+                ..
+            } => flags,
+            TranslateResult::Mapped { .. } => return Err(MapError::HugePageConflict(page.start_address())),
+            _ => return Err(MapError::NotMapped(page.start_address())),
+        };
+        unsafe { mapper.update_flags(page, f(page, flags)) }
+            .map_err(|err| MapError::from_flag_update(err, page))?
+            .flush();
+    }
+    Ok(())
+}
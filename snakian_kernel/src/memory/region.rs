@@ -0,0 +1,228 @@
+//! Bookkeeping for kernel virtual address space.
+//!
+//! Every range of virtual memory the kernel knows about is reserved here under a name: the ranges set up by the
+//! bootloader (kernel image, physical memory mapping, framebuffer), fixed ranges like the heap, and ranges handed
+//! out at runtime for stacks, MMIO windows and large buffers. Runtime allocations come out of a dedicated window
+//! that is checked to be unmapped at boot, so they never collide with anything else.
+
+use core::fmt;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use super::{paging, phys_to_virt, MapError};
+use crate::{prelude::*, sync::Mutex};
+
+/// The start of the window runtime allocations are made from.
+pub const VMALLOC_START: u64 = 0x_6000_0000_0000;
+/// The end of the window runtime allocations are made from. 8 TiB should be plenty.
+pub const VMALLOC_END: u64 = 0x_6800_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// What a virtual region is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    KernelImage,
+    PhysicalMemoryMap,
+    Framebuffer,
+    BootInfo,
+    Heap,
+    Stack,
+    Mmio,
+    Buffer,
+    Other,
+}
+
+/// A reserved range of virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    pub start: VirtAddr,
+    pub size: u64,
+}
+
+impl Region {
+    /// The address right after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    fn overlaps(&self, start: u64, size: u64) -> bool {
+        self.start.as_u64() < start + size && start < self.start.as_u64() + self.size
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:?} {}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.kind,
+            self.name
+        )
+    }
+}
+
+/// An error from the region manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The requested range overlaps with the contained region.
+    Overlaps(&'static str),
+    /// There is no gap large enough in the allocation window.
+    OutOfSpace,
+    /// No region starts at the given address.
+    NotFound,
+    /// Mapping the region failed.
+    Map(MapError),
+}
+
+impl From<MapError> for RegionError {
+    fn from(err: MapError) -> Self {
+        RegionError::Map(err)
+    }
+}
+
+/// All reserved regions, sorted by start address.
+static REGIONS: Mutex<BTreeMap<u64, Region>> = Mutex::new(BTreeMap::new());
+
+/// Reserves a fixed range of virtual memory. Used for ranges whose address is decided somewhere else.
+pub fn reserve(name: &'static str, kind: RegionKind, start: VirtAddr, size: u64) -> Result<Region, RegionError> {
+    let start = start.align_down(PAGE_SIZE);
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mut regions = REGIONS.lock();
+    if let Some(other) = regions.values().find(|r| r.overlaps(start.as_u64(), size)) {
+        return Err(RegionError::Overlaps(other.name));
+    }
+    let region = Region { name, kind, start, size };
+    regions.insert(start.as_u64(), region);
+    Ok(region)
+}
+
+/// Finds a free range of `size` bytes aligned to `align` in the allocation window, and reserves it.
+/// Nothing is mapped; see [`allocate_mapped`] and [`map_mmio`] for that.
+pub fn allocate(name: &'static str, kind: RegionKind, size: u64, align: u64) -> Result<Region, RegionError> {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let align = align.max(PAGE_SIZE);
+    let mut regions = REGIONS.lock();
+
+    // First fit: walk the regions inside the window in order, and look at the gap in front of each one.
+    let mut candidate = align_up(VMALLOC_START, align);
+    for region in regions.range(VMALLOC_START..VMALLOC_END).map(|(_, r)| r) {
+        if candidate + size <= region.start.as_u64() {
+            break;
+        }
+        candidate = candidate.max(align_up(region.end().as_u64(), align));
+    }
+    if candidate + size > VMALLOC_END {
+        return Err(RegionError::OutOfSpace);
+    }
+
+    let region = Region {
+        name,
+        kind,
+        start: VirtAddr::new(candidate),
+        size,
+    };
+    regions.insert(candidate, region);
+    Ok(region)
+}
+
+/// Allocates a region and backs it with freshly allocated frames.
+pub fn allocate_mapped(
+    name: &'static str,
+    kind: RegionKind,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<Region, RegionError> {
+    let region = allocate(name, kind, size, PAGE_SIZE)?;
+    if let Err(err) = paging::map_range(region.start, region.size, flags) {
+        REGIONS.lock().remove(&region.start.as_u64());
+        return Err(err.into());
+    }
+    Ok(region)
+}
+
+/// Maps the physical range `phys..phys + size` as uncached device memory into a newly allocated region.
+/// Returns the virtual address `phys` is mapped at (the region itself starts at the page containing `phys`).
+/// # Safety
+/// The range must be device memory (or otherwise not in use by the kernel).
+pub unsafe fn map_mmio(name: &'static str, phys: PhysAddr, size: u64) -> Result<VirtAddr, RegionError> {
+    let offset = phys.as_u64() % PAGE_SIZE;
+    let region = allocate(name, RegionKind::Mmio, size + offset, PAGE_SIZE)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let first_frame = phys.align_down(PAGE_SIZE).as_u64();
+    for (i, page) in paging::pages_in_range(region.start, region.size).enumerate() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(first_frame + i as u64 * PAGE_SIZE));
+        // SAFETY: the page is part of a fresh region, and the caller guarantees the frame is device memory.
+        if let Err(err) = unsafe { paging::map_page(page, frame, flags) } {
+            // Unmap what was mapped so far, but don't free the frames, they aren't RAM.
+            let mapped = i as u64 * PAGE_SIZE;
+            if mapped > 0 {
+                let _ = unsafe { paging::unmap_range(region.start, mapped, false) };
+            }
+            REGIONS.lock().remove(&region.start.as_u64());
+            return Err(err.into());
+        }
+    }
+    Ok(region.start + offset)
+}
+
+/// Releases the region starting at `start`. The region has to be unmapped by whoever mapped it.
+pub fn free(start: VirtAddr) -> Result<Region, RegionError> {
+    REGIONS
+        .lock()
+        .remove(&start.as_u64())
+        .ok_or(RegionError::NotFound)
+}
+
+/// Returns the region containing the given address.
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    let regions = REGIONS.lock();
+    let (_, region) = regions.range(..=addr.as_u64()).next_back()?;
+    (addr < region.end()).then_some(*region)
+}
+
+/// Calls `f` for every reserved region, in address order.
+pub fn for_each_region(mut f: impl FnMut(&Region)) {
+    for region in REGIONS.lock().values() {
+        f(region);
+    }
+}
+
+/// Prints every reserved region to the serial port.
+pub fn dump_regions() {
+    serial_println!("Virtual memory regions:");
+    for_each_region(|region| serial_println!("  {}", region));
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Makes sure nothing is mapped in the allocation window yet, so allocations can't collide with bootloader mappings.
+pub(super) fn check_window() {
+    let (l4_frame, _) = Cr3::read();
+    // SAFETY: CR3 always points to a valid level 4 table.
+    let l4 = unsafe { &*phys_to_virt(l4_frame.start_address()).as_ptr::<PageTable>() };
+    let first = VirtAddr::new(VMALLOC_START).p4_index();
+    let last = VirtAddr::new(VMALLOC_END - 1).p4_index();
+    for index in u16::from(first)..=u16::from(last) {
+        if !l4[index as usize].is_unused() {
+            warn!(
+                "Virtual allocation window overlaps an existing mapping in L4 entry {}!",
+                index
+            );
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Kernel stacks with guard pages.
+//!
+//! Every kernel stack lives in its own virtual region with an unmapped guard page right below it.
+//! When a stack overflows, the access to the guard page faults instead of silently overwriting whatever
+//! is below the stack, and the fault handlers use [`find_guard_hit`] to name the stack that overflowed.
+//!
+//! Stacks are also painted with [`STACK_PAINT`] when they are set up. Stack usage never erases the paint by itself,
+//! so scanning for the first overwritten word gives the deepest the stack has ever been (the high-water mark).
+
+use core::arch::asm;
+
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{
+    map_range,
+    region::{self, RegionError, RegionKind},
+    translate_addr,
+};
+use crate::{prelude::*, sync::Mutex};
+
+/// The size of a page, and so the size of every guard page.
+const PAGE_SIZE: u64 = 4096;
+/// The maximum amount of stacks that can be registered.
+const MAX_STACKS: usize = 16;
+/// The pattern unused stack memory is filled with.
+pub const STACK_PAINT: u64 = 0x5741_434B_5741_434B; // "KCAWKCAW"
+/// How much space to leave unpainted below the stack pointer when painting the stack we are running on.
+const PAINT_MARGIN: u64 = 512;
+
+/// A kernel stack and its guard page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    /// The name of the stack, used in debug output.
+    pub name: &'static str,
+    /// The start of the unmapped guard page.
+    pub guard: VirtAddr,
+    /// The lowest usable address of the stack.
+    pub bottom: VirtAddr,
+    /// The address right above the stack. This is what the stack pointer starts at.
+    pub top: VirtAddr,
+}
+
+impl KernelStack {
+    /// The usable size of the stack in bytes.
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    /// Returns true if the address is inside of the guard page.
+    pub fn is_guard_hit(&self, addr: VirtAddr) -> bool {
+        addr >= self.guard && addr < self.bottom
+    }
+
+    /// Returns true if the address is inside of the usable part of the stack.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.bottom && addr < self.top
+    }
+
+    /// Measures the deepest this stack has been by looking for the lowest word that isn't paint anymore.
+    pub fn usage(&self) -> StackUsage {
+        let mut ptr = self.bottom.as_ptr::<u64>();
+        let top = self.top.as_ptr::<u64>();
+        // SAFETY: the whole stack is mapped, and reading it doesn't disturb whoever is using it.
+        unsafe {
+            while ptr < top && ptr.read_volatile() == STACK_PAINT {
+                ptr = ptr.add(1);
+            }
+        }
+        StackUsage {
+            name: self.name,
+            size: self.size(),
+            high_water_mark: self.top.as_u64() - ptr as u64,
+        }
+    }
+}
+
+static STACKS: Mutex<[Option<KernelStack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// Registers a stack so overflows into its guard page can be recognised.
+pub fn register_stack(stack: KernelStack) {
+    let mut stacks = STACKS.lock();
+    let slot = stacks
+        .iter_mut()
+        .find(|s| s.is_none())
+        .expect("Too many kernel stacks registered!");
+    *slot = Some(stack);
+    debug!(
+        "Registered stack {} at {:#x}..{:#x} (guard at {:#x})",
+        stack.name,
+        stack.bottom.as_u64(),
+        stack.top.as_u64(),
+        stack.guard.as_u64()
+    );
+}
+
+/// Allocates and maps a new stack of at least `size` bytes, with an unmapped guard page below it, and registers it.
+pub fn allocate_stack(name: &'static str, size: u64) -> Result<KernelStack, RegionError> {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    // The region includes the guard page, so nothing else can be placed right below the stack.
+    let region = region::allocate(name, RegionKind::Stack, PAGE_SIZE + size, PAGE_SIZE)?;
+    let guard = region.start;
+    let bottom = guard + PAGE_SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(err) = map_range(bottom, size, flags) {
+        let _ = region::free(region.start);
+        return Err(err.into());
+    }
+
+    let stack = KernelStack {
+        name,
+        guard,
+        bottom,
+        top: bottom + size,
+    };
+    // SAFETY: the stack was just mapped, and nothing is running on it yet.
+    unsafe { paint(bottom, stack.top) };
+    register_stack(stack);
+    Ok(stack)
+}
+
+/// Finds the bounds of the stack we are currently running on, and registers it as the boot stack.
+///
+/// The bootloader leaves the page below the kernel stack unmapped, so the first unmapped page below the
+/// stack pointer is used as the guard page. `stack_size` is the stack size the bootloader was configured with.
+pub fn register_boot_stack(stack_size: u64) {
+    let rsp: u64;
+    // SAFETY: reading rsp has no side effects.
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+
+    let current = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    let mut bottom = current.start_address();
+    // Don't walk further down than the stack can be large, in case there is something mapped right below it.
+    let lowest = VirtAddr::new(rsp.saturating_sub(stack_size)).align_down(PAGE_SIZE);
+    while bottom > lowest && translate_addr(bottom - PAGE_SIZE).is_some() {
+        bottom -= PAGE_SIZE;
+    }
+    if translate_addr(bottom - PAGE_SIZE).is_some() {
+        warn!("Boot stack at {:#x} has no guard page below it!", bottom.as_u64());
+    }
+    let top = (bottom + stack_size).align_up(PAGE_SIZE);
+
+    // Paint everything below the part of the stack that is in use right now.
+    // SAFETY: everything below rsp is unused, and the margin covers the frame of `paint` itself.
+    unsafe { paint(bottom, VirtAddr::new(rsp - PAINT_MARGIN)) };
+
+    if let Err(err) = region::reserve("boot stack", RegionKind::Stack, bottom - PAGE_SIZE, top - (bottom - PAGE_SIZE)) {
+        warn!("Failed to reserve the boot stack region: {:?}", err);
+    }
+    register_stack(KernelStack {
+        name: "boot",
+        guard: bottom - PAGE_SIZE,
+        bottom,
+        top,
+    });
+}
+
+/// Fills `from..to` with the stack paint.
+/// # Safety
+/// The range must be mapped and unused.
+unsafe fn paint(from: VirtAddr, to: VirtAddr) {
+    let mut ptr = from.as_mut_ptr::<u64>();
+    let end = to.as_mut_ptr::<u64>();
+    // A plain loop instead of `write_bytes`, so no function call puts a frame on the stack we might be painting.
+    while ptr < end {
+        unsafe {
+            ptr.write_volatile(STACK_PAINT);
+            ptr = ptr.add(1);
+        }
+    }
+}
+
+/// How much of a stack has been used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackUsage {
+    pub name: &'static str,
+    /// The usable size of the stack in bytes.
+    pub size: u64,
+    /// The deepest the stack has been since it was painted, in bytes.
+    pub high_water_mark: u64,
+}
+
+impl StackUsage {
+    /// The high-water mark as a percentage of the stack size.
+    pub fn percent(&self) -> u64 {
+        self.high_water_mark * 100 / self.size
+    }
+}
+
+/// Returns the usage of the stack with the given name.
+pub fn high_water_mark(name: &str) -> Option<StackUsage> {
+    let stacks = STACKS.lock();
+    stacks.iter().flatten().find(|s| s.name == name).map(|s| s.usage())
+}
+
+/// Calls `f` with the usage of every registered stack.
+pub fn for_each_stack_usage(mut f: impl FnMut(StackUsage)) {
+    let stacks = *STACKS.lock();
+    for stack in stacks.iter().flatten() {
+        f(stack.usage());
+    }
+}
+
+/// Logs the high-water mark of every registered stack.
+pub fn log_stack_usage() {
+    for_each_stack_usage(|usage| {
+        debug!(
+            "Stack {}: {} of {} bytes used at most ({}%)",
+            usage.name,
+            usage.high_water_mark,
+            usage.size,
+            usage.percent()
+        );
+    });
+}
+
+/// Returns the stack whose guard page contains the given address.
+///
+/// This uses `try_lock` so it is safe to call from fault handlers that might have interrupted a registration.
+pub fn find_guard_hit(addr: VirtAddr) -> Option<KernelStack> {
+    let stacks = STACKS.try_lock()?;
+    stacks.iter().flatten().find(|s| s.is_guard_hit(addr)).copied()
+}
+
+/// Returns the stack that contains the given address.
+pub fn find_stack(addr: VirtAddr) -> Option<KernelStack> {
+    let stacks = STACKS.try_lock()?;
+    stacks.iter().flatten().find(|s| s.contains(addr)).copied()
+}
@@ -1,31 +1,113 @@
 use core::panic::PanicInfo;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use conquer_once::spin::OnceCell;
 use x86_64::instructions::hlt;
-use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::interrupts::{self, without_interrupts};
 
+use crate::backtrace::Backtrace;
 use crate::display::ColorCode;
-use crate::hardware_interrupts::timer::TICKS_UNSAFE;
+use crate::interrupts::hlt_loop;
 use crate::HAS_INIT;
 use crate::prelude::*;
 use crate::display;
+use crate::power::{self, PanicAction};
+use crate::time;
+use crate::watchdog;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+/// How long the panic screen stays in one color.
+const FLASH_INTERVAL_MS: u128 = 500;
+/// Gets to see the message before the panic screen is shown, see [`set_panic_screen_hook`].
+static PANIC_SCREEN_HOOK: OnceCell<fn(&str)> = OnceCell::uninit();
+
+/// Installs a function that is called with the message of every panic screen, right before it is shown. The test
+/// harness uses it to end tests that expect a fatal error. Only the first hook is kept.
+pub fn set_panic_screen_hook(hook: fn(&str)) {
+    PANIC_SCREEN_HOOK.init_once(|| hook);
+}
 
 pub fn panic_handler(panic: &PanicInfo) -> ! {
+    // A panic while showing a panic would just recurse, report it and stop.
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        serial_println!("Panicked while panicking: {:?}", panic.message());
+        interrupts::disable();
+        hlt_loop();
+    }
+    // The panic path takes locks from whatever state the kernel was in.
+    #[cfg(feature = "lockdep")]
+    crate::lockdep::disable();
     serial_println!(
         "Kernal Panic in file {} at line {}",
         panic.location().unwrap().file(),
         panic.location().unwrap().line()
     );
     serial_println!("Panic Reason:{}", panic.message().unwrap());
-    let message = panic.message().unwrap().as_str();
-    panic_runner(
-        panic.location().unwrap().file(),
-        message.unwrap_or("No panic message"),
-    )
+    let backtrace = Backtrace::capture();
+    serial_println!("Backtrace:\n{}", backtrace);
+    // Format the message on the stack, the heap might be the reason we are panicking.
+    let mut message = MessageBuffer::<1024>::new();
+    match panic.message() {
+        Some(args) => {
+            let _ = write!(message, "{}", args);
+        }
+        None => {
+            let _ = write!(message, "No panic message");
+        }
+    }
+    let _ = write!(message, "\n{}", backtrace);
+    panic_runner(panic.location().unwrap().file(), message.as_str())
+}
+
+/// A fixed size buffer that panic messages get formatted into.
+/// Anything that doesn't fit is cut off.
+pub(crate) struct MessageBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> MessageBuffer<N> {
+    pub(crate) const fn new() -> MessageBuffer<N> {
+        MessageBuffer { buf: [0; N], len: 0 }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        // SAFETY: only whole `str`s or their char boundaries are ever copied into the buffer.
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+impl<const N: usize> Write for MessageBuffer<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let space = self.buf.len() - self.len;
+        let mut take = s.len().min(space);
+        // Don't cut a character in half.
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        if take < s.len() {
+            return Err(core::fmt::Error);
+        }
+        Ok(())
+    }
 }
 /// This is the function that runs the animation when the kernel panics.
 // TODO: make this more robust. Add error handling, so it can fall back to a simpler panic animation if it fails. Make it so that it theoretically can't panic.
 pub fn panic_runner(location: &str, message: &str) -> ! {
+    // The panic screen waits with interrupts disabled, that's not a lockup.
+    watchdog::disable();
+    if let Some(hook) = PANIC_SCREEN_HOOK.get() {
+        hook(message);
+    }
+    // The panic was already reported over serial, so the machine can go away right away if it was asked to.
+    match power::panic_action() {
+        PanicAction::Reboot => power::reboot(),
+        PanicAction::Shutdown => power::shutdown(),
+        PanicAction::Halt => {}
+    }
     if !*HAS_INIT.lock() {
         serial_println!("Panic before init, cannot initialize panic writer!");
         // we can't panic if we haven't initialized the hardware
@@ -33,26 +115,30 @@ pub fn panic_runner(location: &str, message: &str) -> ! {
             x86_64::instructions::hlt();
         }
     }
-    unsafe { display::WRITER.get().unwrap().force_unlock() }
-    let mut writer = lock_once!(display::WRITER);
+    // Whatever held the writer was interrupted by the panic and will never run again.
+    let mut writer = unsafe { display::WRITER.get().unwrap().steal() };
     info!("Panic writer initialized!");
     writer.reset();
     // set panic format to be red on white
     writer.color_code = ColorCode::new_with_bg((255, 0, 0), (255, 255, 255));
+    let _ = writeln!(writer, "Kernal Panic at location {} \nPanic Reason:{}", location, message);
+    writer.set_pos(0, 0);
     drop(writer);
 
-    let mut ticks = 0;
+    // Exceptions and interrupt handlers get here with interrupts disabled. The timer never ticks then, so the
+    // animation can't run, but the message is already on screen.
+    if !interrupts::are_enabled() {
+        hlt_loop();
+    }
+
+    let mut flashes = 0;
     let mut color_timer: u64 = 0;
     loop {
         // we want to rely on the littlest amount of code as possible, keep it simple
-        if unsafe { TICKS_UNSAFE } % 10 == 0 {
-            let tick_compare = unsafe { TICKS_UNSAFE };
+        let flash = time::uptime().as_millis() / FLASH_INTERVAL_MS;
+        if flash != flashes {
+            flashes = flash;
             without_interrupts(|| {
-                if ticks != tick_compare {
-                    ticks = tick_compare;
-                } else {
-                    return; // we don't want to do anything if the ticks haven't changed
-                }
                 color_timer += 1;
                 let mut writer = lock_once!(display::WRITER);
                 if color_timer % 2 == 0 {
@@ -68,7 +154,7 @@ pub fn panic_runner(location: &str, message: &str) -> ! {
                    // forces the write position to the beginning of the buffer.
                 writer.set_pos(0, 0);
             });
-            hlt(); // hault the cpu until the next interrupt
         }
+        hlt(); // hault the cpu until the next interrupt
     }
 }
\ No newline at end of file
@@ -0,0 +1,230 @@
+//! Powering off and rebooting the machine.
+//!
+//! [`shutdown`] puts the system into the ACPI S5 (soft off) state: the sleep type comes from the `_S5` package in the
+//! DSDT, and is written to the PM1 control blocks from the FADT. [`reboot`] tries the FADT reset register, then the
+//! 8042 keyboard controller reset line, and finally forces a triple fault.
+//!
+//! Both are meant to work from the panic path as well, so they only print to the serial port. The only thing that
+//! touches the heap is mapping a memory mapped reset register, which few machines have.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::{
+    instructions::{self, port::Port},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::{self, AddressSpace, GenericAddress},
+    interrupts::hlt_loop,
+    memory::region,
+    serial_println,
+};
+
+/// The SLP_EN bit of the PM1 control register.
+const SLP_EN: u16 = 1 << 13;
+/// The SCI_EN bit of the PM1 control register, set once the system is in ACPI mode.
+const SCI_EN: u16 = 1;
+/// AML opcodes used to find the `_S5` package.
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_CHAR: u8 = 0x5c;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ONE_OP: u8 = 0x01;
+
+/// How long to busy wait for something to happen before moving on.
+const WAIT_ITERATIONS: usize = 1_000_000;
+
+/// What to do once a panic has been reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicAction {
+    /// Keep showing the panic screen.
+    Halt,
+    Reboot,
+    Shutdown,
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+/// Sets what the panic handler does after reporting a panic.
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// Returns what the panic handler does after reporting a panic.
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        1 => PanicAction::Reboot,
+        2 => PanicAction::Shutdown,
+        _ => PanicAction::Halt,
+    }
+}
+
+/// The values to write to the SLP_TYP fields of PM1a and PM1b to enter a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Finds the `_S5` package in the DSDT and returns its sleep type values.
+pub fn s5_sleep_type() -> Option<SleepType> {
+    let aml = acpi::dsdt()?.bytes();
+    let start = aml.windows(4).position(|w| w == b"_S5_")?;
+    // It has to be a NameOp (possibly with a root prefix) declaring a package, not just a reference to `_S5`.
+    let is_name = start >= 1 && aml[start - 1] == AML_NAME_OP
+        || start >= 2 && aml[start - 1] == AML_ROOT_CHAR && aml[start - 2] == AML_NAME_OP;
+    if !is_name || *aml.get(start + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+    // Skip the PkgLength (its top two bits say how many bytes follow the lead byte) and the element count.
+    let pkg_length = start + 5;
+    let mut pos = pkg_length + 1 + (*aml.get(pkg_length)? >> 6) as usize + 1;
+    let mut element = || {
+        let value = match *aml.get(pos)? {
+            AML_BYTE_PREFIX => {
+                pos += 1;
+                *aml.get(pos)?
+            }
+            AML_ONE_OP => 1,
+            other => other,
+        };
+        pos += 1;
+        Some(value)
+    };
+    let a = element()?;
+    let b = element()?;
+    Some(SleepType { a, b })
+}
+
+/// Powers the machine off through ACPI. If that doesn't work, interrupts are disabled and the CPU is halted.
+pub fn shutdown() -> ! {
+    serial_println!("Shutting down");
+    if let Err(reason) = acpi_shutdown() {
+        serial_println!("ACPI shutdown failed: {}", reason);
+    }
+    serial_println!("It is now safe to turn off the computer");
+    instructions::interrupts::disable();
+    hlt_loop();
+}
+
+fn acpi_shutdown() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    if fadt.pm1a_control_block == 0 {
+        return Err("no PM1a control block");
+    }
+    let sleep_type = s5_sleep_type().ok_or("no _S5 package in the DSDT")?;
+
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control_block as u16);
+    // SAFETY: the FADT says these are the ACPI PM ports.
+    unsafe {
+        // Switch to ACPI mode first, if the firmware didn't do that already.
+        if pm1a.read() & SCI_EN == 0 && fadt.smi_command_port != 0 && fadt.acpi_enable != 0 {
+            Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+            let mut waited = 0;
+            while pm1a.read() & SCI_EN == 0 && waited < WAIT_ITERATIONS {
+                core::hint::spin_loop();
+                waited += 1;
+            }
+        }
+
+        instructions::interrupts::disable();
+        let value = pm1a.read() & !(0b111 << 10);
+        pm1a.write(value | (sleep_type.a as u16) << 10 | SLP_EN);
+        if fadt.pm1b_control_block != 0 {
+            let mut pm1b = Port::<u16>::new(fadt.pm1b_control_block as u16);
+            let value = pm1b.read() & !(0b111 << 10);
+            pm1b.write(value | (sleep_type.b as u16) << 10 | SLP_EN);
+        }
+    }
+    wait();
+    Err("the machine is still running")
+}
+
+/// Reboots the machine. Tries the ACPI reset register, then the 8042, and finally triple faults.
+pub fn reboot() -> ! {
+    serial_println!("Rebooting");
+    instructions::interrupts::disable();
+    match acpi::fadt().and_then(|fadt| fadt.reset_register.map(|reg| (reg, fadt.reset_value))) {
+        Some((register, value)) => {
+            // SAFETY: the FADT says writing the value to this register resets the system.
+            match unsafe { write_reset_register(register, value) } {
+                Ok(()) => wait(),
+                Err(reason) => serial_println!("ACPI reset failed: {}", reason),
+            }
+        }
+        None => serial_println!("No ACPI reset register"),
+    }
+
+    if acpi::fadt().map_or(true, |fadt| fadt.has_8042()) {
+        serial_println!("Resetting through the 8042");
+        pulse_8042_reset();
+        wait();
+    }
+
+    serial_println!("Forcing a triple fault");
+    triple_fault()
+}
+
+/// # Safety
+/// The register must be the reset register from the FADT.
+unsafe fn write_reset_register(register: GenericAddress, value: u8) -> Result<(), &'static str> {
+    match register.space {
+        AddressSpace::SystemIo => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+        AddressSpace::SystemMemory => {
+            let addr = unsafe { region::map_mmio("acpi reset", PhysAddr::new(register.address), 1) }
+                .map_err(|_| "can't map the reset register")?;
+            unsafe { addr.as_mut_ptr::<u8>().write_volatile(value) };
+        }
+        AddressSpace::PciConfig => {
+            // The address is device (bits 32-47), function (bits 16-31) and register offset (bits 0-15) on bus 0.
+            let device = (register.address >> 32) & 0x1f;
+            let function = (register.address >> 16) & 0x7;
+            let offset = register.address & 0xff;
+            let config_address = 0x8000_0000 | device << 11 | function << 8 | (offset & 0xfc);
+            unsafe {
+                Port::<u32>::new(0xcf8).write(config_address as u32);
+                Port::<u8>::new(0xcfc + (offset & 3) as u16).write(value);
+            }
+        }
+        AddressSpace::Other(_) => return Err("unsupported address space"),
+    }
+    Ok(())
+}
+
+/// Asks the 8042 keyboard controller to pulse the CPU reset line.
+fn pulse_8042_reset() {
+    let mut status = Port::<u8>::new(0x64);
+    // SAFETY: waiting for the input buffer to drain and sending the reset command doesn't affect anything else.
+    unsafe {
+        let mut waited = 0;
+        while status.read() & 0b10 != 0 && waited < WAIT_ITERATIONS {
+            core::hint::spin_loop();
+            waited += 1;
+        }
+        status.write(0xfe);
+    }
+}
+
+/// Loads an empty IDT and raises an exception. With no way to handle it, the CPU triple faults and resets.
+fn triple_fault() -> ! {
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    // SAFETY: the whole point is to crash the CPU.
+    unsafe {
+        instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    hlt_loop();
+}
+
+/// Gives a reset or power off some time to take effect.
+fn wait() {
+    for _ in 0..WAIT_ITERATIONS * 10 {
+        core::hint::spin_loop();
+    }
+}
@@ -1,26 +1,29 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+use crate::sync::IrqMutex;
+
 const SERIAL_PORT_ADDR: u16 = 0x3F8;
 
 lazy_static! {
-    static ref SERIAL_PORT: Mutex<SerialPort> = {
+    static ref SERIAL_PORT: IrqMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(SERIAL_PORT_ADDR) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::new(serial_port)
     };
 }
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        unsafe { SERIAL_PORT.force_unlock() }
-        SERIAL_PORT
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    match SERIAL_PORT.try_lock() {
+        Some(mut port) => port.write_fmt(args).expect("Printing to serial failed"),
+        None => {
+            // Only an exception or a panic in the middle of printing gets here. The port has no state besides the
+            // hardware, so writing through a second handle just interleaves the output instead of hanging.
+            let mut port = unsafe { SerialPort::new(SERIAL_PORT_ADDR) };
+            let _ = port.write_fmt(args);
+        }
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,282 @@
+//! Locks that are shared with interrupt handlers.
+//!
+//! A plain spin lock taken by both kernel code and an interrupt handler deadlocks as soon as the interrupt arrives
+//! while the kernel code holds it. [`IrqMutex`] disables interrupts for as long as it is held. Guards can be nested:
+//! interrupts are only enabled again once the last guard is dropped, and only if they were enabled when the first one
+//! was taken.
+//!
+//! The kernel runs on a single CPU, so with interrupts disabled a held lock can only mean that it is being locked
+//! again from the same context, or from an exception that interrupted the holder. Either way it would spin forever.
+//! Debug builds check for that and panic with both lock sites instead.
+//!
+//! [`Mutex`] is a plain spin lock for data that interrupt handlers don't touch. With the `lockdep` feature, both
+//! kinds of locks report to the [lock dependency validator](crate::lockdep).
+
+use core::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+#[cfg(feature = "lockdep")]
+use core::any::type_name;
+#[cfg(any(debug_assertions, feature = "lockdep"))]
+use core::panic::Location;
+#[cfg(debug_assertions)]
+use core::{ptr, sync::atomic::AtomicPtr};
+
+use spin::MutexGuard as SpinMutexGuard;
+use x86_64::instructions::interrupts;
+
+/// How many [`IrqMutexGuard`]s are alive.
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// Whether interrupts were enabled when the outermost guard was taken.
+static RESTORE_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+/// Disables interrupts and records that one more guard is alive.
+fn push_irq_disable() {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if DEPTH.fetch_add(1, Ordering::Relaxed) == 0 {
+        RESTORE_INTERRUPTS.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Records that a guard is gone, and enables interrupts again if it was the outermost one.
+fn pop_irq_disable() {
+    if DEPTH.fetch_sub(1, Ordering::Relaxed) == 1 && RESTORE_INTERRUPTS.load(Ordering::Relaxed) {
+        interrupts::enable();
+    }
+}
+
+/// Returns how many [`IrqMutex`]es are currently held.
+pub fn held_irq_locks() -> usize {
+    DEPTH.load(Ordering::Relaxed)
+}
+
+/// A spin lock that keeps interrupts disabled while it is held.
+pub struct IrqMutex<T: ?Sized> {
+    /// Where the lock was taken, for the re-entrancy check.
+    #[cfg(debug_assertions)]
+    holder: AtomicPtr<Location<'static>>,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex {
+            #[cfg(debug_assertions)]
+            holder: AtomicPtr::new(ptr::null_mut()),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    /// Disables interrupts and takes the lock.
+    /// # Panics
+    /// In debug builds, if the lock is already held. On a single CPU with interrupts disabled it would never be
+    /// released.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        push_irq_disable();
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquire(self.key(), type_name::<T>(), Location::caller(), false);
+        #[cfg(debug_assertions)]
+        let guard = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => {
+                let holder = self.holder.load(Ordering::Relaxed);
+                // SAFETY: the holder is either null or a `&'static Location` stored by a previous lock.
+                match unsafe { holder.as_ref() } {
+                    Some(holder) => panic!(
+                        "IrqMutex locked again at {}, it is already held since {}",
+                        Location::caller(),
+                        holder
+                    ),
+                    None => panic!("IrqMutex locked again at {}, it is already held", Location::caller()),
+                }
+            }
+        };
+        #[cfg(not(debug_assertions))]
+        let guard = self.inner.lock();
+        self.guard(guard)
+    }
+
+    /// Takes the lock if it is free. Interrupts are only disabled if it is.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        push_irq_disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lockdep")]
+                crate::lockdep::acquire(self.key(), type_name::<T>(), Location::caller(), true);
+                Some(self.guard(guard))
+            }
+            None => {
+                pop_irq_disable();
+                None
+            }
+        }
+    }
+
+    /// Takes the lock even if it is held. Meant for the panic path, which has to get to the screen and the serial
+    /// port no matter what it interrupted.
+    /// # Safety
+    /// Whoever holds the lock must never touch the data again.
+    #[track_caller]
+    pub unsafe fn steal(&self) -> IrqMutexGuard<'_, T> {
+        push_irq_disable();
+        if self.inner.is_locked() {
+            // SAFETY: the holder is gone for good, see above.
+            unsafe { self.inner.force_unlock() };
+        }
+        let guard = self.inner.lock();
+        self.guard(guard)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Identifies the lock to the lock dependency validator.
+    #[cfg(feature = "lockdep")]
+    fn key(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    #[track_caller]
+    fn guard<'a>(&'a self, guard: SpinMutexGuard<'a, T>) -> IrqMutexGuard<'a, T> {
+        #[cfg(debug_assertions)]
+        self.holder.store(
+            Location::caller() as *const Location<'static> as *mut Location<'static>,
+            Ordering::Relaxed,
+        );
+        IrqMutexGuard {
+            mutex: self,
+            guard: ManuallyDrop::new(guard),
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("IrqMutex").field("data", &&*guard).finish(),
+            None => f.write_str("IrqMutex { <locked> }"),
+        }
+    }
+}
+
+/// Gives access to the data of an [`IrqMutex`]. Dropping it unlocks the mutex, then restores interrupts.
+pub struct IrqMutexGuard<'a, T: ?Sized> {
+    #[cfg_attr(not(any(debug_assertions, feature = "lockdep")), allow(dead_code))]
+    mutex: &'a IrqMutex<T>,
+    guard: ManuallyDrop<SpinMutexGuard<'a, T>>,
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.mutex.holder.store(ptr::null_mut(), Ordering::Relaxed);
+        // SAFETY: the guard is never used again.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::release(self.mutex.key());
+        pop_irq_disable();
+    }
+}
+
+/// A spin lock. Unlike [`IrqMutex`] it leaves interrupts alone, so interrupt handlers must never take it.
+pub struct Mutex<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquire(self.key(), type_name::<T>(), Location::caller(), false);
+        MutexGuard {
+            mutex: self,
+            guard: self.inner.lock(),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquire(self.key(), type_name::<T>(), Location::caller(), true);
+        Some(MutexGuard { mutex: self, guard })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Identifies the lock to the lock dependency validator.
+    #[cfg(feature = "lockdep")]
+    fn key(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+/// Gives access to the data of a [`Mutex`]. Dropping it unlocks the mutex.
+pub struct MutexGuard<'a, T: ?Sized> {
+    #[cfg_attr(not(feature = "lockdep"), allow(dead_code))]
+    mutex: &'a Mutex<T>,
+    guard: SpinMutexGuard<'a, T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        crate::lockdep::release(self.mutex.key());
+    }
+}
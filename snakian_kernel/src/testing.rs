@@ -1,6 +1,7 @@
 use core::panic::PanicInfo;
 
 use bootloader_api::{config::Mapping, BootInfo, BootloaderConfig};
+use conquer_once::spin::OnceCell;
 
 use crate::{print, println, serial_println};
 
@@ -9,7 +10,16 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
 
     serial_println!("Running {} tests", tests.len());
     for test in tests {
+        #[cfg(feature = "heap-debug")]
+        let mark = crate::memory::heap::leak_mark();
         test.run();
+        #[cfg(feature = "heap-debug")]
+        {
+            let leaks = crate::memory::heap::report_leaks_since(mark);
+            if leaks > 0 {
+                serial_println!("{} allocations outlived the test", leaks);
+            }
+        }
     }
     testing::exit_qemu(testing::QemuExitCode::Success);
 }
@@ -29,6 +39,23 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+/// Checks the message of the fatal error a test expects, see [`expect_fatal`].
+static EXPECTED_FATAL: OnceCell<fn(&str) -> bool> = OnceCell::uninit();
+
+/// Makes a fatal error end the test instead of showing the panic screen. QEMU exits with success if `check` accepts
+/// the message of the panic screen, and with failure otherwise.
+pub fn expect_fatal(check: fn(&str) -> bool) {
+    EXPECTED_FATAL.init_once(|| check);
+    crate::panic::set_panic_screen_hook(check_expected_fatal);
+}
+
+/// Called by the panic screen once a test expects a fatal error, ends the test.
+fn check_expected_fatal(message: &str) {
+    if let Some(check) = EXPECTED_FATAL.get() {
+        exit_qemu(if check(message) { QemuExitCode::Success } else { QemuExitCode::Failed });
+    }
+}
+
 pub trait Testable {
     fn run(&self) -> ();
 }
@@ -45,12 +72,15 @@ where
 }
 
 pub fn panic_handler(panic: &PanicInfo) -> ! {
+    #[cfg(feature = "lockdep")]
+    crate::lockdep::disable();
     serial_println!(
         "Kernal Panic in file {} at line {}",
         panic.location().unwrap().file(),
         panic.location().unwrap().line()
     );
     serial_println!("Reason:{}", panic.message().unwrap());
+    serial_println!("Backtrace:\n{}", crate::backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     loop {} // if qemu doesn't exit
 }
@@ -62,8 +92,28 @@ pub static TEST_BOOT_CONFIG: BootloaderConfig = {
     config
 };
 
+/// Sets up the entry point and panic handler for an integration test.
+/// Use `test_setup!(init)` for tests that need the kernel to be initialized (heap, interrupts, display).
 #[macro_export]
 macro_rules! test_setup {
+    (init) => {
+        #[no_mangle]
+        pub fn snakian_test_entry(boot_info: &'static mut ::bootloader_api::BootInfo) -> ! {
+            snakian_kernel::init(boot_info);
+            test_main();
+            loop {}
+        }
+
+        #[panic_handler]
+        fn panic(info: &PanicInfo) -> ! {
+            snakian_kernel::testing::panic_handler(info)
+        }
+
+        bootloader_api::entry_point!(
+            snakian_test_entry,
+            config = &snakian_kernel::testing::TEST_BOOT_CONFIG
+        );
+    };
     () => {
         #[no_mangle]
         pub fn snakian_test_entry(_: &'static mut ::bootloader_api::BootInfo) -> ! {
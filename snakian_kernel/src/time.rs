@@ -0,0 +1,147 @@
+//! Time keeping based on the PIT.
+//!
+//! [`init`] programs channel 0 of the PIT to interrupt at [`TIMER_HZ`]. The PIT can only divide its input clock by
+//! an integer, so the actual tick length is derived from the divisor that was programmed rather than from
+//! [`TIMER_HZ`], which keeps [`uptime`] from drifting. Time has a resolution of one tick, and doesn't advance while
+//! interrupts are disabled.
+
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub},
+};
+pub use core::time::Duration;
+
+use x86_64::instructions::{self, interrupts, port::Port};
+
+use crate::{hardware_interrupts::timer, prelude::*};
+
+/// The frequency of the clock driving the PIT, in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// How often the timer interrupt fires, roughly.
+pub const TIMER_HZ: u64 = 1000;
+/// What the PIT input clock is divided by, rounded to the closest divisor.
+const PIT_DIVISOR: u64 = (PIT_FREQUENCY + TIMER_HZ / 2) / TIMER_HZ;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const PIT_RATE_GENERATOR: u8 = 0b0011_0100;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Programs the PIT to tick at [`TIMER_HZ`].
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        // SAFETY: the PIT only drives the timer interrupt, changing its rate changes nothing else.
+        unsafe {
+            Port::<u8>::new(PIT_COMMAND).write(PIT_RATE_GENERATOR);
+            let mut data = Port::<u8>::new(PIT_CHANNEL_0);
+            data.write(PIT_DIVISOR as u8);
+            data.write((PIT_DIVISOR >> 8) as u8);
+        }
+    });
+    info!("PIT ticks every {} ns ({} Hz requested)", ticks_to_nanos(1), TIMER_HZ);
+}
+
+fn ticks_to_nanos(ticks: u64) -> u128 {
+    ticks as u128 * PIT_DIVISOR as u128 * NANOS_PER_SEC / PIT_FREQUENCY as u128
+}
+
+/// The time since the timer started ticking.
+pub fn uptime() -> Duration {
+    let nanos = ticks_to_nanos(timer::ticks());
+    Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+}
+
+/// A point in time, measured by the timer. Only ever increases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(uptime())
+    }
+
+    /// The time since this instant, zero if it is in the future.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(*self)
+    }
+
+    /// # Panics
+    /// If `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).expect("supplied instant is later than self")
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Instant)
+    }
+
+    /// The time between boot and this instant.
+    pub fn since_boot(&self) -> Duration {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:06}s", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
+
+/// Halts the CPU until at least `duration` has passed.
+/// # Panics
+/// If interrupts are disabled, the timer could never wake the CPU up again.
+pub fn sleep(duration: Duration) {
+    if duration.is_zero() {
+        return;
+    }
+    assert!(interrupts::are_enabled(), "sleeping with interrupts disabled would never wake up");
+    // The current tick might be almost over already, so one more is needed to sleep at least `duration`.
+    let deadline = Instant::now() + duration + Duration::from_nanos(ticks_to_nanos(1) as u64);
+    while Instant::now() < deadline {
+        instructions::hlt();
+    }
+}
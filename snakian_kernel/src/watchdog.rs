@@ -0,0 +1,198 @@
+//! Lockup watchdog.
+//!
+//! The periodic interrupt of the RTC is routed through the I/O APIC as an NMI, so it arrives even while the kernel
+//! runs with interrupts disabled. Every one of them checks that the timer tick count moved since the last one. If it
+//! hasn't for [`TIMEOUT_PERIODS`] in a row, the interrupted context is reported as stuck, with its registers and a
+//! backtrace. Each stall is reported once, the watchdog re-arms when the timer ticks again.
+//!
+//! Without an I/O APIC the RTC can only raise a maskable interrupt, which can't see a kernel stuck with interrupts
+//! disabled, so the watchdog stays off.
+//!
+//! The NMI handler selects RTC registers through the CMOS index port, so nothing else may use the CMOS while the
+//! watchdog runs.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{apic, hardware_interrupts::timer, prelude::*};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const RTC_REG_A: u8 = 0x0a;
+const RTC_REG_B: u8 = 0x0b;
+const RTC_REG_C: u8 = 0x0c;
+/// Register B: enable the periodic interrupt.
+const RTC_PERIODIC_ENABLE: u8 = 1 << 6;
+/// Register C: the periodic interrupt fired.
+const RTC_PERIODIC_FLAG: u8 = 1 << 6;
+/// The periodic rate is 32768 Hz >> (rate - 1), 15 is 2 Hz.
+const RTC_RATE: u8 = 15;
+const RTC_IRQ: u8 = 8;
+
+/// How often the watchdog checks the timer, in milliseconds.
+pub const PERIOD_MS: u64 = 500;
+/// How many checks in a row without a timer tick count as a lockup.
+pub const TIMEOUT_PERIODS: u64 = 4;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Checks in a row that saw the same tick count.
+static STALLED: AtomicU64 = AtomicU64::new(0);
+/// Whether the current stall was reported already.
+static REPORTED: AtomicBool = AtomicBool::new(false);
+static CHECKS: AtomicU64 = AtomicU64::new(0);
+static LOCKUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads an RTC register.
+/// # Safety
+/// Nothing else may be using the CMOS index port.
+unsafe fn read_rtc(reg: u8) -> u8 {
+    // Bit 7 of the index would mask NMIs, it's left clear.
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+/// Writes an RTC register.
+/// # Safety
+/// See [`read_rtc`]. Writing RTC registers changes the clock and its interrupts.
+unsafe fn write_rtc(reg: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    }
+}
+
+/// Starts the watchdog. Has to be called after the APIC is set up. Returns false if there is no way to deliver the
+/// watchdog as an NMI.
+pub fn init() -> bool {
+    if !apic::is_enabled() {
+        warn!("Not using the APIC, the lockup watchdog is disabled");
+        return false;
+    }
+    LAST_TICKS.store(timer::ticks(), Ordering::Relaxed);
+    interrupts::without_interrupts(|| {
+        // SAFETY: the watchdog owns the RTC, and its NMI isn't routed yet.
+        unsafe {
+            let rate = read_rtc(RTC_REG_A);
+            write_rtc(RTC_REG_A, (rate & 0xf0) | RTC_RATE);
+            let control = read_rtc(RTC_REG_B);
+            write_rtc(RTC_REG_B, control | RTC_PERIODIC_ENABLE);
+            // A pending flag would keep the RTC from raising the next interrupt.
+            read_rtc(RTC_REG_C);
+        }
+    });
+    if !apic::route_irq_as_nmi(RTC_IRQ) {
+        warn!("Failed to route the RTC as an NMI, the lockup watchdog is disabled");
+        return false;
+    }
+    ENABLED.store(true, Ordering::Release);
+    info!(
+        "Lockup watchdog checks the timer every {} ms, timeout {} ms",
+        PERIOD_MS,
+        PERIOD_MS * TIMEOUT_PERIODS
+    );
+    true
+}
+
+/// Stops reporting lockups. The panic screen spins with interrupts disabled on purpose.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Where an NMI came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmiSource {
+    /// A watchdog check, the timer is fine.
+    Watchdog,
+    /// A watchdog check that found the timer stuck.
+    Lockup(Lockup),
+    /// Something else, e.g. a hardware error.
+    Other,
+}
+
+/// The timer stopped ticking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lockup {
+    /// The tick count the timer is stuck at.
+    pub ticks: u64,
+    /// How long it has been stuck, at least.
+    pub stalled_ms: u64,
+}
+
+impl fmt::Display for Lockup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "watchdog: lockup, timer stuck at tick {} for {} ms",
+            self.ticks, self.stalled_ms
+        )
+    }
+}
+
+/// Called from the NMI handler. Acknowledges the RTC if the NMI is a watchdog check, and checks the timer.
+pub fn handle_nmi() -> NmiSource {
+    if !is_enabled() {
+        return NmiSource::Other;
+    }
+    // SAFETY: the watchdog owns the RTC. Reading register C acknowledges the interrupt, without that the RTC doesn't
+    // raise the next one.
+    if unsafe { read_rtc(RTC_REG_C) } & RTC_PERIODIC_FLAG == 0 {
+        return NmiSource::Other;
+    }
+    CHECKS.fetch_add(1, Ordering::Relaxed);
+    let ticks = timer::ticks();
+    if LAST_TICKS.swap(ticks, Ordering::Relaxed) != ticks {
+        STALLED.store(0, Ordering::Relaxed);
+        REPORTED.store(false, Ordering::Relaxed);
+        return NmiSource::Watchdog;
+    }
+    let stalled = STALLED.fetch_add(1, Ordering::Relaxed) + 1;
+    if stalled < TIMEOUT_PERIODS || REPORTED.swap(true, Ordering::Relaxed) {
+        return NmiSource::Watchdog;
+    }
+    LOCKUPS.fetch_add(1, Ordering::Relaxed);
+    NmiSource::Lockup(Lockup {
+        ticks,
+        stalled_ms: stalled * PERIOD_MS,
+    })
+}
+
+/// Counters of the lockup watchdog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogStats {
+    pub enabled: bool,
+    /// The amount of watchdog NMIs handled.
+    pub checks: u64,
+    /// The amount of lockups reported.
+    pub lockups: u64,
+}
+
+pub fn stats() -> WatchdogStats {
+    WatchdogStats {
+        enabled: is_enabled(),
+        checks: CHECKS.load(Ordering::Relaxed),
+        lockups: LOCKUPS.load(Ordering::Relaxed),
+    }
+}
+
+impl fmt::Display for WatchdogStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "watchdog: {}, {} checks, {} lockups",
+            if self.enabled { "on" } else { "off" },
+            self.checks,
+            self.lockups
+        )
+    }
+}
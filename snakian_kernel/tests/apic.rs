@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::{apic, hardware_interrupts::timer};
+
+snakian_kernel::test_setup!(init);
+
+#[test_case]
+fn timer_ticks_through_the_apic() {
+    // `init` already switched to the APIC, so the PIT has to come in through its overridden GSI.
+    assert!(apic::is_enabled());
+    let start = timer::ticks();
+    // A tick is a few milliseconds, this waits much longer than that without relying on the timer itself.
+    for _ in 0..100_000_000u64 {
+        if timer::ticks() != start {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    panic!("the timer didn't tick after the APIC was enabled");
+}
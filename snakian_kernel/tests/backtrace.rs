@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::backtrace::Backtrace;
+
+snakian_kernel::test_setup!(init);
+
+#[inline(never)]
+fn nested(depth: usize) -> Backtrace {
+    if depth == 0 {
+        Backtrace::capture()
+    } else {
+        nested(depth - 1)
+    }
+}
+
+#[test_case]
+fn capture_follows_the_frame_chain() {
+    let shallow = nested(0);
+    let deep = nested(3);
+    assert!(!shallow.frames().is_empty());
+    assert_eq!(deep.frames().len(), (shallow.frames().len() + 3).min(snakian_kernel::backtrace::MAX_FRAMES));
+}
@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use snakian_kernel::deferred::{self, CAPACITY};
+
+snakian_kernel::test_setup!(init);
+
+static SUM: AtomicU64 = AtomicU64::new(0);
+
+fn add(value: u64) {
+    SUM.fetch_add(value, Ordering::Relaxed);
+}
+
+/// The values passed to [`record`] as decimal digits, in the order it was called.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+fn record(value: u64) {
+    let _ = SEQUENCE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sequence| Some(sequence * 10 + value));
+}
+
+#[test_case]
+fn queued_work_runs_in_order_later() {
+    SEQUENCE.store(0, Ordering::Relaxed);
+    assert!(deferred::defer(record, 1));
+    assert!(deferred::defer(record, 2));
+    assert!(deferred::defer(record, 3));
+    assert!(!deferred::is_empty());
+    assert_eq!(SEQUENCE.load(Ordering::Relaxed), 0);
+    assert_eq!(deferred::run_pending(), 3);
+    assert_eq!(SEQUENCE.load(Ordering::Relaxed), 123);
+    assert_eq!(deferred::stats().depth, 0);
+    assert!(deferred::is_empty());
+}
+
+#[test_case]
+fn full_queue_drops_and_counts() {
+    SUM.store(0, Ordering::Relaxed);
+    let dropped = deferred::stats().dropped;
+    // Interrupts stay off so nothing else queues work in between.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for _ in 0..CAPACITY {
+            assert!(deferred::defer(add, 1));
+        }
+        assert!(!deferred::defer(add, 1));
+    });
+    let stats = deferred::stats();
+    assert_eq!(stats.dropped, dropped + 1);
+    assert_eq!(stats.max_depth, CAPACITY);
+    assert_eq!(deferred::run_pending(), CAPACITY);
+    assert_eq!(SUM.load(Ordering::Relaxed), CAPACITY as u64);
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    arch::asm,
+    hint::black_box,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use snakian_kernel::memory::{
+    self,
+    fault::{register_lazy_region, unregister_lazy_region, LazyKind, LazyRegion},
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+snakian_kernel::test_setup!(init);
+
+const REGION_START: u64 = 0x_5555_0000_0000;
+const STACK_START: u64 = 0x_5555_1000_0000;
+const STACK_PAGES: u64 = 16;
+/// How many pages [`grow`] goes deep.
+const GROW_PAGES: usize = 8;
+
+#[test_case]
+fn lazy_region_is_zero_filled_on_touch() {
+    let start = VirtAddr::new(REGION_START);
+    register_lazy_region(LazyRegion {
+        name: "test",
+        start,
+        size: 4 * 4096,
+        flags: PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        kind: LazyKind::ZeroFill,
+    })
+    .unwrap();
+    assert!(memory::translate_addr(start).is_none());
+
+    let ptr = (start + 4096u64 + 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert!(memory::translate_addr(start + 4096u64).is_some());
+    // Only the touched page gets backed.
+    assert!(memory::translate_addr(start).is_none());
+
+    unsafe { unregister_lazy_region(start) }.unwrap();
+    assert!(memory::translate_addr(start + 4096u64).is_none());
+}
+
+/// Uses a page of stack per call, `depth` calls deep.
+#[inline(never)]
+fn grow(depth: usize) -> usize {
+    let mut page = [0u8; 4096];
+    black_box(&mut page);
+    if depth == 0 {
+        0
+    } else {
+        grow(depth - 1) + 1
+    }
+}
+
+static GROWN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn grow_on_lazy_stack() {
+    GROWN.store(grow(GROW_PAGES), Ordering::Relaxed);
+}
+
+/// Runs `f` with the stack pointer at `top`, and switches back to the current stack afterwards.
+unsafe fn call_on_stack(top: VirtAddr, f: extern "C" fn()) {
+    unsafe {
+        asm!(
+            // r12 is callee saved, so it still holds the old stack pointer when `f` returns.
+            "mov r12, rsp",
+            "mov rsp, {top}",
+            "call {f}",
+            "mov rsp, r12",
+            top = in(reg) top.as_u64(),
+            f = in(reg) f,
+            out("r12") _,
+            clobber_abi("C"),
+        );
+    }
+}
+
+#[test_case]
+fn lazy_stack_grows_while_running_on_it() {
+    let start = VirtAddr::new(STACK_START);
+    let top = start + STACK_PAGES * 4096;
+    register_lazy_region(LazyRegion {
+        name: "test stack",
+        start,
+        size: STACK_PAGES * 4096,
+        flags: PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        kind: LazyKind::Stack,
+    })
+    .unwrap();
+
+    // Every new page is first touched by a push or a call, which faults with the stack pointer on the missing page.
+    unsafe { call_on_stack(top, grow_on_lazy_stack) };
+    assert_eq!(GROWN.load(Ordering::Relaxed), GROW_PAGES);
+    assert!(memory::translate_addr(top - GROW_PAGES as u64 * 4096).is_some());
+    // The bottom of the stack was never reached.
+    assert!(memory::translate_addr(start).is_none());
+
+    unsafe { unregister_lazy_region(start) }.unwrap();
+}
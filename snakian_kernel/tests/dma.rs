@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::memory::{
+    self,
+    dma::{CacheMode, DmaBuffer, DmaConstraints, DmaError},
+    frame_stats,
+};
+
+snakian_kernel::test_setup!(init);
+
+#[test_case]
+fn isa_buffer_respects_constraints() {
+    let free = {
+        let mut buffer = DmaBuffer::<u8>::new(3 * 4096, DmaConstraints::ISA, CacheMode::Uncached).unwrap();
+        let phys = buffer.phys_addr().as_u64();
+        assert!(phys + buffer.size() <= 0x100_0000);
+        assert_eq!(phys / 0x1_0000, (phys + buffer.size() - 1) / 0x1_0000);
+        assert!(buffer.iter().all(|b| *b == 0));
+
+        buffer[4096] = 0x42;
+        // The virtual mapping has to point at the physical range the device gets.
+        let translated = memory::translate_addr(buffer.virt_addr() + 4096u64).unwrap();
+        assert_eq!(translated.as_u64(), phys + 4096);
+        frame_stats().free
+    };
+    // Dropping the buffer gives the frames back (page tables created for the mapping stay around).
+    assert_eq!(frame_stats().free, free + 3);
+}
+
+#[test_case]
+fn aligned_write_combining_buffer() {
+    let constraints = DmaConstraints {
+        align: 0x1_0000,
+        ..DmaConstraints::BELOW_4G
+    };
+    let mut buffer = DmaBuffer::<u32>::new(256, constraints, CacheMode::WriteCombining).unwrap();
+    assert!(buffer.phys_addr().is_aligned(0x1_0000u64));
+    assert_eq!(buffer.phys_addr_of(3).as_u64(), buffer.phys_addr().as_u64() + 12);
+    buffer[255] = 7;
+    assert_eq!(buffer[255], 7);
+}
+
+#[test_case]
+fn overflowing_size_is_rejected() {
+    let err = DmaBuffer::<u64>::new(usize::MAX / 4, DmaConstraints::ANY, CacheMode::WriteBack).unwrap_err();
+    assert_eq!(err, DmaError::InvalidRequest);
+}
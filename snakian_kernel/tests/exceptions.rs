@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{fmt::Write, panic::PanicInfo};
+
+use snakian_kernel::exceptions::{self, ErrorCodeDescription};
+
+snakian_kernel::test_setup!(init);
+
+/// Formats into a stack buffer, the decoded strings are short.
+struct Buffer {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+fn decode(vector: u8, error_code: u64) -> Buffer {
+    let mut buffer = Buffer { buf: [0; 128], len: 0 };
+    write!(buffer, "{}", ErrorCodeDescription { vector, error_code }).unwrap();
+    buffer
+}
+
+fn as_str(buffer: &Buffer) -> &str {
+    core::str::from_utf8(&buffer.buf[..buffer.len]).unwrap()
+}
+
+#[test_case]
+fn breakpoint_resumes() {
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn selector_error_codes() {
+    let gdt = decode(exceptions::GENERAL_PROTECTION_FAULT, 2 << 3);
+    assert_eq!(as_str(&gdt), "GDT index 2");
+    let idt = decode(exceptions::GENERAL_PROTECTION_FAULT, 0x80 << 3 | 0b11);
+    assert_eq!(as_str(&idt), "IDT index 128, external event");
+    let none = decode(exceptions::GENERAL_PROTECTION_FAULT, 0);
+    assert_eq!(as_str(&none), "not segment related");
+}
+
+#[test_case]
+fn page_fault_error_codes() {
+    let write = decode(exceptions::PAGE_FAULT, 0b11);
+    assert!(as_str(&write).starts_with("[PW---] write of a present page"));
+    let fetch = decode(exceptions::PAGE_FAULT, 1 << 4);
+    assert!(as_str(&fetch).starts_with("[----I] instruction fetch"));
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::{
+    lock_once,
+    memory::{
+        fault::{register_lazy_region, LazyKind, LazyRegion},
+        OFFSET_PAGE_TABLE,
+    },
+    testing::{self, QemuExitCode},
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+snakian_kernel::test_setup!(init);
+
+const REGION_START: u64 = 0x_5555_2000_0000;
+
+/// A lazy page touched while the page table is locked can't be backed. The fault handler must not spin on the lock,
+/// the fault has to end up on the panic screen naming the lock.
+#[test_case]
+fn lazy_fault_with_page_table_held_is_fatal() {
+    let start = VirtAddr::new(REGION_START);
+    register_lazy_region(LazyRegion {
+        name: "reentry",
+        start,
+        size: 4096,
+        flags: PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        kind: LazyKind::ZeroFill,
+    })
+    .unwrap();
+    testing::expect_fatal(|message| message.contains("the page table is locked"));
+
+    let _page_table = lock_once!(OFFSET_PAGE_TABLE);
+    let value = unsafe { start.as_ptr::<u64>().read_volatile() };
+    // Only reached if the fault was resolved anyway.
+    snakian_kernel::serial_println!("lazy page read {} while the page table was locked", value);
+    testing::exit_qemu(QemuExitCode::Failed);
+}
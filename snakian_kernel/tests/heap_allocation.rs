@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::panic::PanicInfo;
+
+use snakian_kernel::memory::heap::{self, HEAP_SIZE};
+
+snakian_kernel::test_setup!(init);
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn many_boxes() {
+    // Only works if freed memory gets reused.
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn stats_follow_allocations() {
+    const BLOCKS: usize = 8;
+    const BLOCK_SIZE: usize = 64;
+    let before = heap::heap_stats();
+    let mut blocks: [Option<Box<[u8; BLOCK_SIZE]>>; BLOCKS] = Default::default();
+    for block in blocks.iter_mut() {
+        *block = Some(Box::new([0; BLOCK_SIZE]));
+    }
+    let allocated = heap::heap_stats();
+    assert_eq!(allocated.live_allocations, before.live_allocations + BLOCKS);
+    assert_eq!(allocated.total_allocations, before.total_allocations + BLOCKS);
+    // The debug allocator asks for more to fit its header and red zones.
+    if !cfg!(feature = "heap-debug") {
+        assert_eq!(allocated.bytes_in_use, before.bytes_in_use + BLOCKS * BLOCK_SIZE);
+    }
+    assert!(allocated.bytes_in_use >= before.bytes_in_use + BLOCKS * BLOCK_SIZE);
+    assert!(allocated.peak_bytes_in_use >= allocated.bytes_in_use);
+
+    // Freeing every other block leaves holes between the ones that are still alive.
+    for block in blocks.iter_mut().step_by(2) {
+        *block = None;
+    }
+    let freed = heap::heap_stats();
+    assert_eq!(freed.live_allocations, allocated.live_allocations - BLOCKS / 2);
+    assert_eq!(freed.total_allocations, allocated.total_allocations);
+    assert_eq!(freed.bytes_in_use, allocated.bytes_in_use - (allocated.bytes_in_use - before.bytes_in_use) / 2);
+    assert_eq!(freed.peak_bytes_in_use, allocated.peak_bytes_in_use);
+    assert!(freed.largest_free_block <= freed.free_bytes);
+    assert!(freed.largest_free_block >= allocated.largest_free_block);
+    if heap::ALLOCATOR_NAME == "bump" {
+        // Nothing is reused until every allocation is gone.
+        assert_eq!(freed.free_bytes, allocated.free_bytes);
+    } else {
+        assert!(freed.free_bytes >= allocated.free_bytes + BLOCKS / 2 * BLOCK_SIZE);
+    }
+    if freed.largest_free_block == allocated.largest_free_block {
+        // The holes only add small pieces of free memory.
+        assert!(freed.fragmentation() >= allocated.fragmentation());
+    }
+    assert!(freed.fragmentation() <= 100);
+    drop(blocks);
+}
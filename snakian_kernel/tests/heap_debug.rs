@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::panic::PanicInfo;
+
+use snakian_kernel::memory::heap;
+
+snakian_kernel::test_setup!(init);
+
+#[test_case]
+fn allocations_record_their_call_site() {
+    let mark = heap::leak_mark();
+    let first = Box::new(1u64);
+    let second = Box::new(2u64);
+    let mut allocations = [None; 2];
+    assert_eq!(heap::allocations_since(mark, &mut allocations), 2);
+    let [Some(newest), Some(oldest)] = allocations else {
+        panic!("allocations missing: {:?}", allocations);
+    };
+    assert_eq!(oldest.ptr, &*first as *const u64 as *const u8);
+    assert_eq!(newest.ptr, &*second as *const u64 as *const u8);
+    // Both went through the same allocator code, only the return address into this function differs.
+    assert_ne!(oldest.site, newest.site);
+    if let (Some(oldest), Some(newest)) = (oldest.site.caller(), newest.site.caller()) {
+        assert!(oldest.1.ends_with("allocations_record_their_call_site"), "caller is {}", oldest.1);
+        assert!(newest.1.ends_with("allocations_record_their_call_site"), "caller is {}", newest.1);
+        assert_ne!(oldest.0, newest.0);
+    }
+}
+
+#[test_case]
+fn freed_allocations_are_not_reported() {
+    let mark = heap::leak_mark();
+    drop(Box::new([0u8; 64]));
+    let kept = Box::new(3u32);
+    let mut allocations = [None; 4];
+    assert_eq!(heap::allocations_since(mark, &mut allocations), 1);
+    assert_eq!(allocations[0].unwrap().ptr, &*kept as *const u32 as *const u8);
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use snakian_kernel::irq::{self, IrqError, IrqReturn};
+
+snakian_kernel::test_setup!(init);
+
+/// Nothing in the kernel uses this vector, so it can be raised with `int` to test the dispatcher.
+const TEST_VECTOR: u8 = 0x80;
+
+#[test_case]
+fn handlers_registered_at_runtime_are_called() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let id = irq::register(TEST_VECTOR, "test", |context| {
+        assert_eq!(context.vector, TEST_VECTOR);
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::Handled
+    })
+    .unwrap();
+    let count = irq::count(TEST_VECTOR);
+    unsafe { core::arch::asm!("int 0x80") };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(irq::count(TEST_VECTOR), count + 1);
+
+    assert!(irq::unregister(id));
+    assert!(!irq::unregister(id));
+    unsafe { core::arch::asm!("int 0x80") };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn shared_vectors_call_every_handler() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let first = irq::register_shared(TEST_VECTOR, "first", |_| {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::NotHandled
+    })
+    .unwrap();
+    let second = irq::register_shared(TEST_VECTOR, "second", |_| {
+        CALLS.fetch_add(10, Ordering::Relaxed);
+        IrqReturn::Handled
+    })
+    .unwrap();
+    // An exclusive handler can't join a shared vector.
+    assert_eq!(
+        irq::register(TEST_VECTOR, "exclusive", |_| IrqReturn::Handled),
+        Err(IrqError::Busy(TEST_VECTOR))
+    );
+    unsafe { core::arch::asm!("int 0x80") };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 11);
+
+    assert!(irq::unregister(first));
+    assert!(irq::unregister(second));
+    assert!(!irq::has_handlers(TEST_VECTOR));
+}
+
+#[test_case]
+fn exceptions_are_rejected() {
+    assert_eq!(
+        irq::register(14, "page fault", |_| IrqReturn::Handled),
+        Err(IrqError::InvalidVector(14))
+    );
+}
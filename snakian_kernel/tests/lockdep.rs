@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::{
+    lockdep,
+    sync::{IrqMutex, Mutex},
+};
+
+snakian_kernel::test_setup!(init);
+
+static OUTER: Mutex<()> = Mutex::new(());
+static INNER: IrqMutex<()> = IrqMutex::new(());
+
+#[test_case]
+fn nesting_records_a_dependency() {
+    let before = lockdep::stats().unwrap();
+    {
+        let _outer = OUTER.lock();
+        let _inner = INNER.lock();
+        assert_eq!(lockdep::stats().unwrap().held, before.held + 2);
+    }
+    let after = lockdep::stats().unwrap();
+    assert!(after.enabled);
+    assert_eq!(after.held, before.held);
+    assert_eq!(after.dependencies, before.dependencies + 1);
+
+    // The same order again doesn't add anything.
+    {
+        let _outer = OUTER.lock();
+        let _inner = INNER.lock();
+    }
+    assert_eq!(lockdep::stats().unwrap().dependencies, after.dependencies);
+}
+
+#[test_case]
+fn locks_can_be_released_out_of_order() {
+    let outer = OUTER.lock();
+    let inner = INNER.lock();
+    drop(outer);
+    drop(inner);
+    assert_eq!(lockdep::stats().unwrap().held, 0);
+}
+
+#[test_case]
+fn try_lock_in_reverse_order_is_allowed() {
+    drop((OUTER.lock(), INNER.lock()));
+    let _inner = INNER.lock();
+    // It can't deadlock, it would just fail.
+    assert!(OUTER.try_lock().is_some());
+    assert!(lockdep::is_enabled());
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks, panic_info_message)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use bootloader_api::BootInfo;
+use snakian_kernel::{
+    serial_println,
+    sync::Mutex,
+    testing::{self, QemuExitCode},
+};
+
+bootloader_api::entry_point!(test_entry, config = &testing::TEST_BOOT_CONFIG);
+
+fn test_entry(boot_info: &'static mut BootInfo) -> ! {
+    snakian_kernel::init(boot_info);
+    test_main();
+    snakian_kernel::interrupts::hlt_loop()
+}
+
+static A: Mutex<u8> = Mutex::new(0);
+static B: Mutex<u16> = Mutex::new(0);
+/// The lines B was locked at while holding A, and A while holding B.
+static A_THEN_B: AtomicU32 = AtomicU32::new(0);
+static B_THEN_A: AtomicU32 = AtomicU32::new(0);
+
+#[test_case]
+fn inverted_order_names_both_sites() {
+    {
+        let _a = A.lock();
+        A_THEN_B.store(line!() + 1, Ordering::Relaxed);
+        let _b = B.lock();
+    }
+    let _b = B.lock();
+    B_THEN_A.store(line!() + 1, Ordering::Relaxed);
+    let _a = A.lock();
+    serial_println!("[locking in the opposite order didn't panic]");
+    testing::exit_qemu(QemuExitCode::Failed);
+}
+
+/// Formats into a stack buffer, the heap could be locked while panicking.
+struct Buffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Buffer<N> {
+    fn new() -> Self {
+        Buffer { buf: [0; N], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Write for Buffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > N {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Checks that the site a lock was taken at is part of the report.
+fn names_site(message: &str, line: u32) -> bool {
+    let mut site = Buffer::<64>::new();
+    let _ = write!(site, "tests/lockdep_inversion.rs:{}:", line);
+    message.contains(site.as_str())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    snakian_kernel::lockdep::disable();
+    let mut message = Buffer::<1024>::new();
+    let _ = write!(message, "{}", info.message().unwrap());
+    let message = message.as_str();
+    serial_println!("{}", message);
+    let ok = message.contains("lock order inversion")
+        && names_site(message, A_THEN_B.load(Ordering::Relaxed))
+        && names_site(message, B_THEN_A.load(Ordering::Relaxed));
+    if ok {
+        serial_println!("[ok]");
+        testing::exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[the report doesn't name both sites]");
+        testing::exit_qemu(QemuExitCode::Failed);
+    }
+    snakian_kernel::interrupts::hlt_loop()
+}
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use core::panic::PanicInfo;
+
+use snakian_kernel::mce::{BankError, McaCode};
+
+snakian_kernel::test_setup!(init);
+
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_EN: u64 = 1 << 60;
+const STATUS_PCC: u64 = 1 << 57;
+
+#[test_case]
+fn cache_errors_are_decoded() {
+    assert_eq!(McaCode(0x0135).to_string(), "L1 data cache data read error");
+    // The correction report filtering bit doesn't change the error.
+    assert_eq!(McaCode(0x1135).to_string(), "L1 data cache data read error");
+    assert_eq!(McaCode(0x000e).to_string(), "L2 generic cache hierarchy error");
+}
+
+#[test_case]
+fn tlb_errors_are_decoded() {
+    assert_eq!(McaCode(0x0010).to_string(), "L0 instruction TLB error");
+    assert_eq!(McaCode(0x0016).to_string(), "L2 data TLB error");
+}
+
+#[test_case]
+fn memory_controller_errors_are_decoded() {
+    assert_eq!(McaCode(0x0080).to_string(), "memory controller generic error on channel 0");
+    assert_eq!(McaCode(0x00a3).to_string(), "memory controller write error on channel 3");
+}
+
+#[test_case]
+fn simple_and_unknown_codes() {
+    assert_eq!(McaCode(0x0000).to_string(), "no error");
+    assert_eq!(McaCode(0x0400).to_string(), "internal timer error");
+    assert_eq!(McaCode(0x0020).to_string(), "unknown error 0x0020");
+}
+
+#[test_case]
+fn uncorrected_error_shows_its_flags() {
+    let error = BankError {
+        bank: 3,
+        status: STATUS_VAL | STATUS_OVER | STATUS_UC | STATUS_EN | STATUS_PCC | 0x0135,
+        addr: Some(0x1234_5000),
+        misc: None,
+    };
+    assert!(error.is_uncorrected());
+    assert!(error.is_context_corrupt());
+    let text = error.to_string();
+    assert!(text.starts_with("bank 3: uncorrected L1 data cache data read error"));
+    assert!(text.ends_with(", overflow, signaled, context corrupt, addr 0x12345000"));
+}
+
+#[test_case]
+fn corrected_error_has_no_flags() {
+    let error = BankError {
+        bank: 0,
+        status: STATUS_VAL | (5 << 38) | 0x0080,
+        addr: None,
+        misc: None,
+    };
+    assert!(!error.is_uncorrected());
+    assert_eq!(error.corrected_count(), 5);
+    let text = error.to_string();
+    assert!(text.starts_with("bank 0: corrected memory controller generic error on channel 0"));
+    assert!(!text.contains("overflow"));
+    assert!(!text.contains("signaled"));
+    assert!(!text.contains("context corrupt"));
+    assert!(text.ends_with(", 5 corrected"));
+}
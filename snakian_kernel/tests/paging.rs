@@ -0,0 +1,144 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::{
+    lock_once,
+    memory::{self, frame_stats, paging, MapError, OFFSET_PAGE_TABLE},
+};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, Page, PageTableFlags, PhysFrame, Translate},
+    PhysAddr, VirtAddr,
+};
+
+snakian_kernel::test_setup!(init);
+
+const RANGE_START: u64 = 0x_5555_1000_0000;
+/// Physical addresses above the RAM of the test machine, nothing is mapped at them yet.
+const MMIO_PHYS: u64 = 0x7_0000_0000;
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// The flags of the page at `addr`, without the ones the CPU sets on access.
+fn flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    match lock_once!(OFFSET_PAGE_TABLE).translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY),
+        _ => None,
+    }
+}
+
+/// Maps and unmaps the range once, so the page tables it needs exist and don't show up in the frame counts.
+fn prepare(start: VirtAddr, size: u64) {
+    paging::map_range(start, size, FLAGS).unwrap();
+    unsafe { paging::unmap_range(start, size, true) }.unwrap();
+}
+
+#[test_case]
+fn map_range_rolls_back_on_failure() {
+    let start = VirtAddr::new(RANGE_START);
+    prepare(start, 4 * 4096);
+    let taken = start + 2 * 4096u64;
+    paging::map_range(taken, 4096, FLAGS).unwrap();
+    let free = frame_stats().free;
+
+    let err = paging::map_range(start, 4 * 4096, FLAGS).unwrap_err();
+    assert!(matches!(err, MapError::AlreadyMapped { page, .. } if page == taken));
+    // The pages before the conflict are unmapped again and their frames are back.
+    assert_eq!(flags(start), None);
+    assert_eq!(flags(start + 4096u64), None);
+    assert_eq!(flags(start + 3 * 4096u64), None);
+    assert_eq!(frame_stats().free, free);
+    // The page that was there before is untouched.
+    assert_eq!(flags(taken), Some(FLAGS));
+
+    unsafe { paging::unmap_range(taken, 4096, true) }.unwrap();
+}
+
+#[test_case]
+fn unmap_range_frees_the_frames() {
+    let start = VirtAddr::new(RANGE_START + 0x10_0000);
+    prepare(start, 3 * 4096);
+    let free = frame_stats().free;
+    paging::map_range(start, 3 * 4096, FLAGS).unwrap();
+    assert_eq!(frame_stats().free, free - 3);
+    unsafe { start.as_mut_ptr::<u64>().write_volatile(42) };
+
+    unsafe { paging::unmap_range(start, 3 * 4096, true) }.unwrap();
+    assert_eq!(frame_stats().free, free);
+    assert_eq!(flags(start), None);
+    assert_eq!(
+        unsafe { paging::unmap_range(start, 4096, true) },
+        Err(MapError::NotMapped(start))
+    );
+}
+
+#[test_case]
+fn change_flags_replaces_the_flags() {
+    let start = VirtAddr::new(RANGE_START + 0x20_0000);
+    paging::map_range(start, 2 * 4096, FLAGS).unwrap();
+    let read_only = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    unsafe { paging::change_flags(start, 2 * 4096, read_only) }.unwrap();
+    assert_eq!(flags(start), Some(read_only));
+    assert_eq!(flags(start + 4096u64), Some(read_only));
+    unsafe { paging::unmap_range(start, 2 * 4096, true) }.unwrap();
+
+    assert_eq!(
+        unsafe { paging::change_flags(start, 4096, FLAGS) },
+        Err(MapError::NotMapped(start))
+    );
+}
+
+#[test_case]
+fn identity_map_mmio_is_uncached_and_repeatable() {
+    let phys = PhysAddr::new(MMIO_PHYS);
+    let virt = unsafe { paging::identity_map_mmio(phys, 2 * 4096) }.unwrap();
+    assert_eq!(virt.as_u64(), MMIO_PHYS);
+    assert_eq!(memory::translate_addr(virt + 4096u64), Some(phys + 4096u64));
+    assert!(flags(virt).unwrap().contains(PageTableFlags::NO_CACHE));
+    // Mapping the same window again is fine.
+    assert_eq!(unsafe { paging::identity_map_mmio(phys, 2 * 4096) }, Ok(virt));
+    unsafe { paging::unmap_range(virt, 2 * 4096, false) }.unwrap();
+}
+
+#[test_case]
+fn identity_map_mmio_rejects_a_cached_mapping() {
+    let phys = PhysAddr::new(MMIO_PHYS + 0x10_0000);
+    let virt = VirtAddr::new(phys.as_u64());
+    let page = Page::containing_address(virt);
+    unsafe { paging::map_page(page, PhysFrame::containing_address(phys), FLAGS) }.unwrap();
+
+    assert_eq!(
+        unsafe { paging::identity_map_mmio(phys, 4096) },
+        Err(MapError::Cached(virt))
+    );
+    assert_eq!(flags(virt), Some(FLAGS));
+    unsafe { paging::unmap_range(virt, 4096, false) }.unwrap();
+}
+
+#[test_case]
+fn identity_map_mmio_rolls_back_on_failure() {
+    let phys = PhysAddr::new(MMIO_PHYS + 0x20_0000);
+    let virt = VirtAddr::new(phys.as_u64());
+    // The first page is already part of another window, the last one is mapped somewhere else entirely.
+    unsafe { paging::identity_map_mmio(phys, 4096) }.unwrap();
+    let taken = virt + 3 * 4096u64;
+    let other = PhysFrame::containing_address(PhysAddr::new(MMIO_PHYS + 0x30_0000));
+    unsafe { paging::map_page(Page::containing_address(taken), other, FLAGS) }.unwrap();
+
+    let err = unsafe { paging::identity_map_mmio(phys, 4 * 4096) }.unwrap_err();
+    assert!(matches!(err, MapError::AlreadyMapped { page, .. } if page == taken));
+    // The pages mapped before the conflict are gone again, the ones that were there before are untouched.
+    assert_eq!(flags(virt + 4096u64), None);
+    assert_eq!(flags(virt + 2 * 4096u64), None);
+    assert!(flags(virt).unwrap().contains(PageTableFlags::NO_CACHE));
+    assert_eq!(flags(taken), Some(FLAGS));
+
+    unsafe { paging::unmap_range(virt, 4096, false) }.unwrap();
+    unsafe { paging::unmap_range(taken, 4096, false) }.unwrap();
+}
@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{hint::black_box, panic::PanicInfo, ptr::addr_of};
+
+use snakian_kernel::memory::region::{self, RegionKind};
+use x86_64::VirtAddr;
+
+snakian_kernel::test_setup!(init);
+
+/// Zero initialized, so it lives in `.bss` and takes no space in the ELF file.
+static mut BSS: [u8; 64 * 4096] = [0; 64 * 4096];
+
+#[test_case]
+fn kernel_image_region_covers_bss() {
+    // SAFETY: only the address is taken.
+    let bss = black_box(unsafe { addr_of!(BSS) });
+    let last = VirtAddr::from_ptr(bss) + (64 * 4096 - 1) as u64;
+    let region = region::find(last).expect("the end of .bss is not in any region");
+    assert_eq!(region.kind, RegionKind::KernelImage);
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::sync::{self, IrqMutex};
+use x86_64::instructions::interrupts;
+
+snakian_kernel::test_setup!(init);
+
+static FIRST: IrqMutex<u32> = IrqMutex::new(0);
+static SECOND: IrqMutex<u32> = IrqMutex::new(0);
+
+#[test_case]
+fn interrupts_are_disabled_while_held() {
+    assert!(interrupts::are_enabled());
+    {
+        let mut first = FIRST.lock();
+        *first += 1;
+        assert!(!interrupts::are_enabled());
+        {
+            let _second = SECOND.lock();
+            assert_eq!(sync::held_irq_locks(), 2);
+        }
+        // The outer guard still keeps them disabled.
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(sync::held_irq_locks(), 0);
+}
+
+#[test_case]
+fn interrupts_stay_disabled_if_they_were() {
+    interrupts::without_interrupts(|| {
+        drop(FIRST.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn try_lock_fails_while_held() {
+    let first = FIRST.lock();
+    assert!(FIRST.try_lock().is_none());
+    assert_eq!(sync::held_irq_locks(), 1);
+    drop(first);
+    assert!(FIRST.try_lock().is_some());
+}
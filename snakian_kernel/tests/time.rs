@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use snakian_kernel::time::{self, Duration, Instant};
+
+snakian_kernel::test_setup!(init);
+
+#[test_case]
+fn sleep_waits_at_least_the_duration() {
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(50));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(50), "slept only {:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(500), "slept {:?}", elapsed);
+}
+
+#[test_case]
+fn uptime_advances() {
+    let before = time::uptime();
+    time::sleep(Duration::from_millis(5));
+    assert!(time::uptime() > before);
+}
+
+#[test_case]
+fn instants_are_ordered() {
+    let earlier = Instant::now();
+    let later = earlier + Duration::from_millis(10);
+    assert!(later > earlier);
+    assert_eq!(later - earlier, Duration::from_millis(10));
+    assert_eq!(earlier.checked_duration_since(later), None);
+    assert_eq!(earlier.saturating_duration_since(later), Duration::ZERO);
+}
@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(snakian_kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{hint, panic::PanicInfo};
+
+use snakian_kernel::watchdog::{self, TIMEOUT_PERIODS};
+use x86_64::instructions::interrupts;
+
+snakian_kernel::test_setup!(init);
+
+#[test_case]
+fn spinning_with_interrupts_disabled_is_reported() {
+    // Without an I/O APIC there is no watchdog to test.
+    if !watchdog::is_enabled() {
+        return;
+    }
+    let before = watchdog::stats();
+    interrupts::without_interrupts(|| {
+        // The checks keep coming as NMIs while the timer can't tick. The first one might still see the last tick.
+        while watchdog::stats().checks <= before.checks + TIMEOUT_PERIODS + 1 {
+            hint::spin_loop();
+        }
+    });
+    assert_eq!(watchdog::stats().lockups, before.lockups + 1);
+}